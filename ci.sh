@@ -16,3 +16,11 @@ cargo build --target thumbv6m-none-eabi --features 'log'
 cargo build --target thumbv6m-none-eabi --features 'defmt'
 cargo build --target thumbv6m-none-eabi --features 'log,firmware-logs'
 cargo build --target thumbv6m-none-eabi --features 'defmt,firmware-logs'
+cargo build --target thumbv6m-none-eabi --features 'defmt,compressed-firmware'
+cargo build --target thumbv6m-none-eabi --features 'defmt,embedded-storage'
+cargo build --target thumbv6m-none-eabi --features 'defmt,verify-firmware,compressed-firmware'
+
+# build host tools
+#=====================================
+
+(cd cyw43-compress; cargo build)
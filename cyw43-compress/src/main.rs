@@ -0,0 +1,424 @@
+//! Compress firmware and CLM blobs for the `compressed-firmware` feature of `cyw43`.
+//!
+//! Usage: `cyw43-compress <input> <output>`, e.g.
+//! `cyw43-compress firmware/43439A0.bin firmware/43439A0.bin.z`.
+//!
+//! See `src/compress.rs` in `cyw43` for the format.
+
+#![cfg_attr(test, allow(incomplete_features, stable_features), feature(async_fn_in_trait))]
+
+use std::process::exit;
+use std::{env, fs};
+
+const MAGIC: &[u8; 4] = b"CYWZ";
+const WINDOW_SIZE: usize = 2048;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 31;
+
+/// How many earlier positions with the same hash to try per match search.
+const MAX_CHAIN: usize = 512;
+
+const HASH_BITS: u32 = 14;
+
+fn hash(data: &[u8]) -> usize {
+    let v = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Finds the longest earlier matches within the window, using hash chains of 3-byte prefixes.
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    /// Positions before this one are in the chains.
+    inserted: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; data.len()],
+            inserted: 0,
+        }
+    }
+
+    fn insert_until(&mut self, pos: usize) {
+        while self.inserted < pos {
+            let i = self.inserted;
+            if i + MIN_MATCH <= self.data.len() {
+                let h = hash(&self.data[i..]);
+                self.prev[i] = self.head[h];
+                self.head[h] = i;
+            }
+            self.inserted += 1;
+        }
+    }
+
+    /// Longest match for the data at `pos`, as `(distance, length)`.
+    fn find(&mut self, pos: usize) -> Option<(usize, usize)> {
+        self.insert_until(pos);
+        if pos + MIN_MATCH > self.data.len() {
+            return None;
+        }
+
+        let max_len = MAX_MATCH.min(self.data.len() - pos);
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[hash(&self.data[pos..])];
+        let mut chain = 0;
+        while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+            let len = self.data[candidate..]
+                .iter()
+                .zip(&self.data[pos..pos + max_len])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= MIN_MATCH && best.map_or(true, |(_, best_len)| len > best_len) {
+                best = Some((pos - candidate, len));
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            chain += 1;
+        }
+        best
+    }
+}
+
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    let mut matcher = Matcher::new(data);
+    let mut flag_pos = 0;
+    let mut items = 8;
+    let mut pos = 0;
+    while pos < data.len() {
+        if items == 8 {
+            flag_pos = out.len();
+            out.push(0);
+            items = 0;
+        }
+
+        let mut found = matcher.find(pos);
+        // Lazy matching: emit a literal if the next position has a longer match.
+        if let Some((_, len)) = found {
+            if let Some((_, next_len)) = matcher.find(pos + 1) {
+                if next_len > len + 1 {
+                    found = None;
+                }
+            }
+        }
+
+        match found {
+            Some((dist, len)) => {
+                let item = (dist - 1) | (len - MIN_MATCH) << 11;
+                out.extend_from_slice(&(item as u16).to_le_bytes());
+                pos += len;
+            }
+            None => {
+                out[flag_pos] |= 1 << items;
+                out.push(data[pos]);
+                pos += 1;
+            }
+        }
+        items += 1;
+    }
+    out
+}
+
+/// Reference decompressor, to check the output before writing it.
+fn decompress(blob: &[u8]) -> Option<Vec<u8>> {
+    if blob.len() < 8 || &blob[..4] != MAGIC {
+        return None;
+    }
+    let len = u32::from_le_bytes(blob[4..8].try_into().unwrap()) as usize;
+    let mut input = blob[8..].iter().copied();
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        let flags = input.next()?;
+        for bit in 0..8 {
+            if out.len() >= len {
+                break;
+            }
+            if flags & 1 << bit != 0 {
+                out.push(input.next()?);
+            } else {
+                let item = u16::from_le_bytes([input.next()?, input.next()?]) as usize;
+                let dist = (item & 0x7ff) + 1;
+                let start = out.len().checked_sub(dist)?;
+                for i in 0..(item >> 11) + MIN_MATCH {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+    out.truncate(len);
+    Some(out)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} <input> <output>", args[0]);
+        exit(2);
+    }
+
+    let data = fs::read(&args[1]).unwrap_or_else(|e| {
+        eprintln!("can't read {}: {}", args[1], e);
+        exit(1);
+    });
+
+    let compressed = compress(&data);
+    if decompress(&compressed).as_deref() != Some(&data[..]) {
+        eprintln!("internal error: compressed data doesn't round trip");
+        exit(1);
+    }
+
+    fs::write(&args[2], &compressed).unwrap_or_else(|e| {
+        eprintln!("can't write {}: {}", args[2], e);
+        exit(1);
+    });
+    println!(
+        "{}: {} -> {} bytes ({:.1}%)",
+        args[1],
+        data.len(),
+        compressed.len(),
+        compressed.len() as f64 * 100.0 / data.len() as f64
+    );
+}
+
+/// The decoder of `cyw43`, built against stand-ins for the `cyw43` items it uses.
+#[cfg(test)]
+#[allow(dead_code, unknown_lints, unexpected_cfgs)]
+#[path = "../../src/compress.rs"]
+mod decoder;
+
+#[cfg(test)]
+use tests::{firmware, ClmError, InitError};
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    use super::decoder::{Decompressor, Error};
+    use super::*;
+
+    pub mod firmware {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct FirmwareReadError;
+
+        pub trait FirmwareSource {
+            fn len(&self) -> usize;
+
+            async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError>;
+        }
+
+        impl FirmwareSource for &[u8] {
+            fn len(&self) -> usize {
+                <[u8]>::len(self)
+            }
+
+            async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError> {
+                buf.copy_from_slice(&self[offset..][..buf.len()]);
+                Ok(())
+            }
+        }
+    }
+
+    pub enum ClmError {
+        Read,
+        Corrupt,
+    }
+
+    impl From<firmware::FirmwareReadError> for ClmError {
+        fn from(_: firmware::FirmwareReadError) -> Self {
+            Self::Read
+        }
+    }
+
+    pub enum InitError {
+        FirmwareRead,
+        FirmwareCorrupt,
+    }
+
+    impl From<firmware::FirmwareReadError> for InitError {
+        fn from(_: firmware::FirmwareReadError) -> Self {
+            Self::FirmwareRead
+        }
+    }
+
+    /// Runs `fut` to completion. Reading from a slice never waits, so there's nothing to wake.
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(|_| RawWaker::new(&(), &VTABLE), |_| {}, |_| {}, |_| {});
+        let waker = unsafe { Waker::from_raw(RawWaker::new(&(), &VTABLE)) };
+        let mut cx = Context::from_waker(&waker);
+        let mut fut = pin!(fut);
+        loop {
+            if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    /// Decompress `blob` with the decoder of `cyw43`, and check its tail against the output.
+    fn target_decompress(blob: &[u8]) -> Result<Vec<u8>, Error> {
+        block_on(async {
+            let mut src = blob;
+            let mut decompressor = Decompressor::new(&mut src).await?;
+            let mut out = Vec::new();
+            while let Some(chunk) = decompressor.next_chunk().await? {
+                out.extend_from_slice(chunk);
+            }
+            let tail_len = out.len().min(WINDOW_SIZE);
+            assert_eq!(decompressor.tail(), &out[out.len() - tail_len..]);
+            Ok(out)
+        })
+    }
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed).as_deref(), Some(data));
+        assert_eq!(target_decompress(&compressed).unwrap(), data);
+        compressed
+    }
+
+    enum Item {
+        Literal(u8),
+        Match { dist: usize, len: usize },
+    }
+
+    /// Encode `items` by hand, to produce exact matches the compressor might not pick.
+    fn encode(len: usize, items: &[Item]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+        for group in items.chunks(8) {
+            let flag_pos = out.len();
+            out.push(0);
+            for (i, item) in group.iter().enumerate() {
+                match *item {
+                    Item::Literal(b) => {
+                        out[flag_pos] |= 1 << i;
+                        out.push(b);
+                    }
+                    Item::Match { dist, len } => {
+                        let item = (dist - 1) | (len - MIN_MATCH) << 11;
+                        out.extend_from_slice(&(item as u16).to_le_bytes());
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Bytes without repeated 3-byte sequences.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x1234_5678u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(round_trip(&[]).len(), 8);
+    }
+
+    #[test]
+    fn literals_only() {
+        let data: Vec<u8> = (0..=255).collect();
+        let compressed = round_trip(&data);
+        // One flag byte per 8 literals, all bits set.
+        assert_eq!(compressed.len(), 8 + data.len() + data.len() / 8);
+        assert!(compressed[8..].iter().step_by(9).all(|&flags| flags == 0xff));
+    }
+
+    #[test]
+    fn max_length_matches() {
+        let data = vec![0xaa; 10 * MAX_MATCH + 1];
+        let compressed = round_trip(&data);
+        assert!(compressed.len() < 8 + 3 * 12);
+
+        let items = [
+            Item::Literal(1),
+            Item::Literal(2),
+            Item::Literal(3),
+            Item::Match {
+                dist: 3,
+                len: MAX_MATCH,
+            },
+        ];
+        let expected: Vec<u8> = [1, 2, 3].iter().copied().cycle().take(3 + MAX_MATCH).collect();
+        assert_eq!(target_decompress(&encode(expected.len(), &items)).unwrap(), expected);
+    }
+
+    #[test]
+    fn distance_is_window_size() {
+        let mut data = noise(WINDOW_SIZE);
+        let mut items: Vec<Item> = data.iter().map(|&b| Item::Literal(b)).collect();
+        items.push(Item::Match {
+            dist: WINDOW_SIZE,
+            len: MIN_MATCH + 5,
+        });
+        data.extend_from_within(..MIN_MATCH + 5);
+        assert_eq!(target_decompress(&encode(data.len(), &items)).unwrap(), data);
+
+        // The compressor may also match at the edge of the window.
+        round_trip(&data);
+    }
+
+    #[test]
+    fn overlapping_match() {
+        // A match longer than its distance copies its own output.
+        let items = [Item::Literal(7), Item::Literal(8), Item::Match { dist: 2, len: 11 }];
+        let expected = [7, 8, 7, 8, 7, 8, 7, 8, 7, 8, 7, 8, 7];
+        assert_eq!(target_decompress(&encode(expected.len(), &items)).unwrap(), expected);
+    }
+
+    #[test]
+    fn chunks_and_window_wrap() {
+        let mut data = noise(3 * WINDOW_SIZE + 123);
+        data.extend_from_within(100..1100);
+        data.extend(noise(700));
+        round_trip(&data);
+    }
+
+    #[test]
+    fn truncated() {
+        let data = noise(3000);
+        let compressed = compress(&data);
+        for len in [0, 4, 7, 8, 9, compressed.len() / 2, compressed.len() - 1] {
+            assert_eq!(
+                target_decompress(&compressed[..len]),
+                Err(Error::Corrupt),
+                "len {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut bad_magic = compress(b"hello hello hello");
+        bad_magic[0] = b'X';
+        assert_eq!(target_decompress(&bad_magic), Err(Error::Corrupt));
+
+        // A match reaching before the start of the data.
+        let items = [Item::Literal(1), Item::Match { dist: 2, len: 3 }];
+        assert_eq!(target_decompress(&encode(4, &items)), Err(Error::Corrupt));
+
+        // The header promises more than the data holds.
+        let mut too_long = compress(b"hello hello hello");
+        too_long[4] += 1;
+        assert_eq!(target_decompress(&too_long), Err(Error::Corrupt));
+    }
+}
@@ -124,9 +124,10 @@ where
         }
     }
 
-    pub async fn write(&mut self, write: &[u32]) -> u32 {
+    pub async fn write(&mut self, cmd: u32, write: &[&[u32]]) -> u32 {
         self.sm.set_enable(false);
-        let write_bits = write.len() * 32 - 1;
+        let write_words = 1 + write.iter().map(|w| w.len()).sum::<usize>();
+        let write_bits = write_words * 32 - 1;
         let read_bits = 31;
 
         defmt::trace!("write={} read={}", write_bits, read_bits);
@@ -139,7 +140,11 @@ where
 
         self.sm.set_enable(true);
 
-        self.sm.dma_push(dma.reborrow(), write).await;
+        // The state machine stalls on an empty FIFO, so the segments can be pushed one after another.
+        self.sm.dma_push(dma.reborrow(), slice::from_ref(&cmd)).await;
+        for &w in write.iter().filter(|w| !w.is_empty()) {
+            self.sm.dma_push(dma.reborrow(), w).await;
+        }
 
         let mut status = 0;
         self.sm.dma_pull(dma.reborrow(), slice::from_mut(&mut status)).await;
@@ -176,9 +181,9 @@ where
     SM: PioStateMachine,
     DMA: Channel,
 {
-    async fn cmd_write(&mut self, write: &[u32]) -> u32 {
+    async fn cmd_write(&mut self, cmd: u32, write: &[&[u32]]) -> u32 {
         self.cs.set_low();
-        let status = self.write(write).await;
+        let status = self.write(cmd, write).await;
         self.cs.set_high();
         status
     }
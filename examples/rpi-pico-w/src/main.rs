@@ -59,6 +59,8 @@ async fn main(spawner: Spawner) {
     //     probe-rs-cli download 43439A0_clm.bin --format bin --chip RP2040 --base-address 0x10140000
     //let fw = unsafe { core::slice::from_raw_parts(0x10100000 as *const u8, 224190) };
     //let clm = unsafe { core::slice::from_raw_parts(0x10140000 as *const u8, 4752) };
+    // Blobs that aren't memory mapped, e.g. on an external flash chip, can be passed as any
+    // `cyw43::FirmwareSource` instead, such as a `cyw43::FlashSource` or `cyw43::FnSource`.
 
     let pwr = Output::new(p.PIN_23, Level::Low);
     let cs = Output::new(p.PIN_25, Level::High);
@@ -73,10 +75,13 @@ async fn main(spawner: Spawner) {
     let spi = PioSpi::new(sm, cs, p.PIN_24, p.PIN_29, dma);
 
     let state = singleton!(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+    // Boards other than the Pico W need their own NVRAM, e.g. `cyw43::Nvram::parse` of the board's
+    // `.txt` NVRAM file, or the Pico W one with some values changed with `Nvram::set`.
+    let nvram = cyw43::Nvram::pico_w();
+    let (net_device, mut control, runner) = unwrap!(cyw43::new(state, pwr, spi, fw, &nvram).await);
     unwrap!(spawner.spawn(wifi_task(runner)));
 
-    control.init(clm).await;
+    unwrap!(control.init(clm, cyw43::Config::default()).await);
     control
         .set_power_management(cyw43::PowerManagementMode::PowerSave)
         .await;
@@ -101,8 +106,14 @@ async fn main(spawner: Spawner) {
 
     unwrap!(spawner.spawn(net_task(stack)));
 
-    //control.join_open(env!("WIFI_NETWORK")).await;
-    control.join_wpa2(env!("WIFI_NETWORK"), env!("WIFI_PASSWORD")).await;
+    //control.join_open(env!("WIFI_NETWORK"), cyw43::AggregationConfig::default()).await;
+    control
+        .join_wpa2(
+            env!("WIFI_NETWORK"),
+            env!("WIFI_PASSWORD"),
+            cyw43::AggregationConfig::default(),
+        )
+        .await;
 
     // And now we can use it!
 
@@ -212,9 +223,12 @@ impl MySpi {
 }
 
 impl cyw43::SpiBusCyw43 for MySpi {
-    async fn cmd_write(&mut self, write: &[u32]) -> u32 {
+    async fn cmd_write(&mut self, cmd: u32, write: &[&[u32]]) -> u32 {
         self.cs.set_low();
-        self.write(write).await;
+        self.write(slice::from_ref(&cmd)).await;
+        for w in write {
+            self.write(w).await;
+        }
 
         let mut status = 0;
         self.read(slice::from_mut(&mut status)).await;
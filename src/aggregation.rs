@@ -0,0 +1,62 @@
+/// Largest block ack window the firmware accepts.
+pub const MAX_BA_WSIZE: u8 = 64;
+
+/// Most MPDUs the firmware puts in an A-MPDU.
+pub const MAX_AMPDU_MPDU: u8 = 32;
+
+/// A-MPDU aggregation settings, see [`Control::set_aggregation`](crate::Control::set_aggregation).
+///
+/// Larger values raise throughput, smaller ones lower the latency of other traffic behind a burst.
+/// The receive factor, `ampdu_rx_factor`, isn't configurable: setting it crashes the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AggregationConfig {
+    ba_wsize: u8,
+    mpdu: u8,
+}
+
+/// Invalid [`AggregationConfig`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AggregationError {
+    /// The block ack window isn't in `1..=MAX_BA_WSIZE`.
+    BaWsize,
+    /// The MPDUs per A-MPDU aren't in `1..=MAX_AMPDU_MPDU`, or more than the block ack window.
+    Mpdu,
+}
+
+impl AggregationConfig {
+    /// Block ack window of `ba_wsize` MPDUs, and at most `mpdu` MPDUs per A-MPDU.
+    pub const fn new(ba_wsize: u8, mpdu: u8) -> Result<Self, AggregationError> {
+        if ba_wsize == 0 || ba_wsize > MAX_BA_WSIZE {
+            return Err(AggregationError::BaWsize);
+        }
+        if mpdu == 0 || mpdu > MAX_AMPDU_MPDU || mpdu > ba_wsize {
+            return Err(AggregationError::Mpdu);
+        }
+        Ok(Self { ba_wsize, mpdu })
+    }
+
+    /// Block ack window, the firmware's `ampdu_ba_wsize`.
+    pub fn ba_wsize(&self) -> u8 {
+        self.ba_wsize
+    }
+
+    /// Most MPDUs per A-MPDU, the firmware's `ampdu_mpdu`.
+    pub fn mpdu(&self) -> u8 {
+        self.mpdu
+    }
+
+    /// Values read from the firmware. `None` if they are out of the ranges [`new`](Self::new) accepts.
+    pub(crate) fn from_firmware(ba_wsize: u32, mpdu: u32) -> Option<Self> {
+        let ba_wsize = u8::try_from(ba_wsize).ok()?;
+        let mpdu = u8::try_from(mpdu).ok()?;
+        Self::new(ba_wsize, mpdu).ok()
+    }
+}
+
+impl Default for AggregationConfig {
+    fn default() -> Self {
+        Self { ba_wsize: 8, mpdu: 4 }
+    }
+}
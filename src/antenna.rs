@@ -0,0 +1,39 @@
+/// Antenna used for receiving and transmitting, see [`Control::set_antenna`](crate::Control::set_antenna).
+///
+/// Which physical antenna is connected to which antenna port depends on the board.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Antenna {
+    /// Antenna port 0, the chip or PCB antenna on most boards, including the Raspberry Pi Pico W.
+    Chip,
+    /// Antenna port 1, usually an external antenna on a U.FL connector.
+    External,
+    /// Receive diversity: the firmware picks the antenna with the better signal, and transmits on
+    /// the one it last received on.
+    Diversity,
+}
+
+impl Antenna {
+    /// Value of the `ANTDIV` ioctl.
+    pub(crate) fn antdiv(self) -> u32 {
+        match self {
+            Self::Chip => 0,
+            Self::External => 1,
+            Self::Diversity => 3,
+        }
+    }
+
+    /// Value of the `TXANT` ioctl. 3 means the antenna last received on.
+    pub(crate) fn txant(self) -> u32 {
+        self.antdiv()
+    }
+
+    pub(crate) fn from_antdiv(antdiv: u32) -> Option<Self> {
+        match antdiv {
+            0 => Some(Self::Chip),
+            1 => Some(Self::External),
+            3 => Some(Self::Diversity),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::task::Poll;
+
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_1::digital::OutputPin;
+
+use crate::bus::{Bus, Transport};
+use crate::chip::Chip;
+use crate::consts::*;
+use crate::fmt::Bytes;
+use crate::InitError;
+
+/// Largest HCI packet exchanged with the Bluetooth controller, including the H4 packet type byte.
+/// Fits any HCI event, and ACL packets with up to 1019 bytes of data.
+pub const BT_MAX_PACKET_LEN: usize = 1024;
+
+/// How long to wait for the Bluetooth firmware to start, and for the controller to wake up.
+const BT_READY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait for room in the host-to-controller buffer before dropping a packet.
+const BT_TX_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Size of the packet header in the ring buffers: 24-bit payload length and the H4 packet type.
+const HEADER_LEN: usize = 4;
+
+/// A packet buffer holds the ring buffer header followed by the payload, padded to a word. The
+/// H4 packet is the last header byte (the packet type) and the payload.
+const SLOT_LEN: usize = (HEADER_LEN - 1 + BT_MAX_PACKET_LEN + 3) & !3;
+
+/// Error returned by [`BtDriver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BtError {
+    /// The packet is empty or longer than [`BT_MAX_PACKET_LEN`].
+    InvalidLength,
+    /// The received packet doesn't fit the buffer. It stays queued, and can be read with a larger buffer.
+    BufferTooSmall,
+}
+
+/// One packet in flight between the [`BtDriver`] and the runner. `len` is the length of the H4
+/// packet, 0 if the slot is free.
+struct Slot {
+    len: Cell<usize>,
+    buf: RefCell<[u8; SLOT_LEN]>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            len: Cell::new(0),
+            buf: RefCell::new([0; SLOT_LEN]),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Wakers {
+    rx: WakerRegistration,
+    tx: WakerRegistration,
+    runner: WakerRegistration,
+}
+
+/// Bluetooth state, shared between the [`Runner`](crate::Runner) and the [`BtDriver`].
+///
+/// Holds one HCI packet per direction. Further packets wait in the controller's buffers.
+pub struct BtState {
+    rx: Slot,
+    tx: Slot,
+    wakers: RefCell<Wakers>,
+}
+
+impl BtState {
+    pub fn new() -> Self {
+        Self {
+            rx: Slot::new(),
+            tx: Slot::new(),
+            wakers: Default::default(),
+        }
+    }
+}
+
+/// HCI transport to the Bluetooth controller, for a Bluetooth host stack to run on.
+///
+/// Packets use H4 framing: the packet type (1 for commands, 2 for ACL data, 4 for events, ...)
+/// followed by the HCI packet.
+pub struct BtDriver<'a> {
+    state: &'a BtState,
+}
+
+impl<'a> BtDriver<'a> {
+    pub(crate) fn new(state: &'a BtState) -> Self {
+        Self { state }
+    }
+
+    /// Receive an H4 packet from the controller into `buf`. Returns its length.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, BtError> {
+        let rx = &self.state.rx;
+        let len = poll_fn(|cx| match rx.len.get() {
+            0 => {
+                self.state.wakers.borrow_mut().rx.register(cx.waker());
+                Poll::Pending
+            }
+            len => Poll::Ready(len),
+        })
+        .await;
+
+        if len > buf.len() {
+            return Err(BtError::BufferTooSmall);
+        }
+        buf[..len].copy_from_slice(&rx.buf.borrow()[HEADER_LEN - 1..][..len]);
+        rx.len.set(0);
+        self.state.wakers.borrow_mut().runner.wake();
+        Ok(len)
+    }
+
+    /// Send an H4 packet to the controller. Waits until the previous packet was handed to the controller.
+    ///
+    /// Concurrent writes are sent one after the other: a packet is only copied to the TX slot once
+    /// it is free, in the same step that marks it as taken.
+    pub async fn write(&self, packet: &[u8]) -> Result<(), BtError> {
+        if packet.is_empty() || packet.len() > BT_MAX_PACKET_LEN {
+            return Err(BtError::InvalidLength);
+        }
+
+        let tx = &self.state.tx;
+        poll_fn(|cx| match tx.len.get() {
+            0 => {
+                tx.buf.borrow_mut()[HEADER_LEN - 1..][..packet.len()].copy_from_slice(packet);
+                tx.len.set(packet.len());
+                Poll::Ready(())
+            }
+            _ => {
+                self.state.wakers.borrow_mut().tx.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await;
+
+        self.state.wakers.borrow_mut().runner.wake();
+        Ok(())
+    }
+}
+
+/// Backplane addresses of the HCI ring buffers and their indices.
+#[derive(Default)]
+struct BufAddrs {
+    host2bt_buf: u32,
+    host2bt_in: u32,
+    host2bt_out: u32,
+    bt2host_buf: u32,
+    bt2host_in: u32,
+    bt2host_out: u32,
+}
+
+/// The runner's side of the Bluetooth shared bus: firmware download, and moving HCI packets
+/// between the [`BtState`] and the controller's ring buffers.
+pub(crate) struct BtRunner<'a> {
+    state: &'a BtState,
+    addrs: BufAddrs,
+    /// Our write index into the host-to-controller buffer.
+    host2bt_in: u32,
+    /// Our read index into the controller-to-host buffer.
+    bt2host_out: u32,
+    /// Reading from the controller stopped because the RX slot was full.
+    rx_stalled: bool,
+}
+
+impl<'a> BtRunner<'a> {
+    pub(crate) fn new(state: &'a BtState) -> Self {
+        Self {
+            state,
+            addrs: BufAddrs::default(),
+            host2bt_in: 0,
+            bt2host_out: 0,
+            rx_stalled: false,
+        }
+    }
+
+    /// Download `firmware` to the Bluetooth core, start it and set up the HCI buffers.
+    pub(crate) async fn init<PWR: OutputPin, T: Transport>(
+        &mut self,
+        bus: &mut Bus<PWR, T>,
+        chip: &Chip,
+        firmware: &[u8],
+    ) -> Result<(), InitError> {
+        let base = chip.bluetooth_base_address.ok_or(InitError::BluetoothUnsupported)?;
+
+        info!("loading bt fw");
+        bus.bp_write32(base + BT2WLAN_PWRUP_ADDR, BT2WLAN_PWRUP_WAKE).await;
+        Timer::after(Duration::from_millis(2)).await;
+        self.upload_firmware(bus, base, firmware).await?;
+
+        info!("waiting for bt init...");
+        let deadline = Instant::now() + BT_READY_TIMEOUT;
+        while bus.bp_read32(BT_CTRL_REG_ADDR).await & BTSDIO_REG_FW_RDY == 0 {
+            if Instant::now() > deadline {
+                return Err(InitError::BluetoothNotReady);
+            }
+            Timer::after(Duration::from_millis(1)).await;
+        }
+
+        let ram_base = bus.bp_read32(WLAN_RAM_BASE_REG_ADDR).await;
+        self.addrs = BufAddrs {
+            host2bt_buf: ram_base + BTSDIO_OFFSET_HOST_WRITE_BUF,
+            host2bt_in: ram_base + BTSDIO_OFFSET_HOST2BT_IN,
+            host2bt_out: ram_base + BTSDIO_OFFSET_HOST2BT_OUT,
+            bt2host_buf: ram_base + BTSDIO_OFFSET_HOST_READ_BUF,
+            bt2host_in: ram_base + BTSDIO_OFFSET_BT2HOST_IN,
+            bt2host_out: ram_base + BTSDIO_OFFSET_BT2HOST_OUT,
+        };
+        self.host2bt_in = bus.bp_read32(self.addrs.host2bt_in).await;
+        self.bt2host_out = bus.bp_read32(self.addrs.bt2host_out).await;
+        self.rx_stalled = false;
+
+        // Keep the controller awake, so packets can be written to it at any time.
+        let ctrl = bus.bp_read32(HOST_CTRL_REG_ADDR).await;
+        bus.bp_write32(HOST_CTRL_REG_ADDR, ctrl | BTSDIO_REG_WAKE_BT).await;
+        let deadline = Instant::now() + BT_READY_TIMEOUT;
+        while bus.bp_read32(BT_CTRL_REG_ADDR).await & BTSDIO_REG_BT_AWAKE == 0 {
+            if Instant::now() > deadline {
+                return Err(InitError::BluetoothNotReady);
+            }
+            Timer::after(Duration::from_millis(1)).await;
+        }
+
+        let ctrl = bus.bp_read32(HOST_CTRL_REG_ADDR).await;
+        bus.bp_write32(HOST_CTRL_REG_ADDR, ctrl | BTSDIO_REG_SW_RDY).await;
+        self.toggle_intr(bus).await;
+
+        info!("bt init done");
+        Ok(())
+    }
+
+    /// Write the data records of a firmware patch to the Bluetooth core's memory.
+    ///
+    /// The patch starts with a length-prefixed version string and one more byte, followed by
+    /// records of a byte count, a 16-bit big endian address, a record type and the data. A record
+    /// without data ends the patch.
+    async fn upload_firmware<PWR: OutputPin, T: Transport>(
+        &mut self,
+        bus: &mut Bus<PWR, T>,
+        base: u32,
+        firmware: &[u8],
+    ) -> Result<(), InitError> {
+        const ERR: InitError = InitError::BluetoothFirmware;
+
+        let version_len = *firmware.first().ok_or(ERR)? as usize;
+        let version = firmware.get(1..1 + version_len).ok_or(ERR)?;
+        debug!("bt fw version {:02x}", Bytes(version));
+        let mut records = firmware.get(version_len + 2..).ok_or(ERR)?;
+
+        let mut addr_hi = 0;
+        loop {
+            let [len, a1, a0, kind, ref rest @ ..] = *records else {
+                return Err(ERR);
+            };
+            if len == 0 {
+                return Ok(());
+            }
+            let data = rest.get(..len as usize).ok_or(ERR)?;
+            records = &rest[len as usize..];
+
+            match kind {
+                BTFW_RECORD_DATA => {
+                    let addr = base + addr_hi + u16::from_be_bytes([a1, a0]) as u32;
+                    write_unaligned(bus, addr, data).await;
+                }
+                BTFW_RECORD_EXTENDED_ADDRESS => {
+                    let [b1, b0, ..] = *data else {
+                        return Err(ERR);
+                    };
+                    addr_hi = (u16::from_be_bytes([b1, b0]) as u32) << 16;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Wait until there is a packet to send, or room again for a received packet.
+    pub(crate) async fn wait(&self) {
+        let state = self.state;
+        poll_fn(|cx| {
+            if state.tx.len.get() != 0 || (self.rx_stalled && state.rx.len.get() == 0) {
+                Poll::Ready(())
+            } else {
+                state.wakers.borrow_mut().runner.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    /// Handle a Bluetooth interrupt from the controller.
+    pub(crate) async fn handle_irq<PWR: OutputPin, T: Transport>(&mut self, bus: &mut Bus<PWR, T>, chip: &Chip) {
+        bus.bp_write32(chip.sdiod_core_base_address + SDIOD_INT_STATUS, SDIOD_I_HMB_FC_CHANGE)
+            .await;
+        self.poll(bus).await;
+    }
+
+    /// Send the pending packet, if any, and receive packets until the controller has no more or
+    /// the RX slot is full.
+    pub(crate) async fn poll<PWR: OutputPin, T: Transport>(&mut self, bus: &mut Bus<PWR, T>) {
+        if self.state.tx.len.get() != 0 {
+            self.send(bus).await;
+        }
+        while self.receive(bus).await {}
+    }
+
+    async fn send<PWR: OutputPin, T: Transport>(&mut self, bus: &mut Bus<PWR, T>) {
+        let len = self.state.tx.len.get();
+        let frame_len = (HEADER_LEN - 1 + len + 3) & !3;
+
+        let deadline = Instant::now() + BT_TX_TIMEOUT;
+        loop {
+            let out = bus.bp_read32(self.addrs.host2bt_out).await;
+            let used = (self.host2bt_in + BTSDIO_FWBUF_SIZE - out) % BTSDIO_FWBUF_SIZE;
+            // Keep a word free, so a full buffer can be told from an empty one.
+            if (BTSDIO_FWBUF_SIZE - used) as usize > frame_len {
+                break;
+            }
+            if Instant::now() > deadline {
+                warn!("bt tx buffer full, dropping packet");
+                self.tx_done();
+                return;
+            }
+            Timer::after(Duration::from_millis(1)).await;
+        }
+
+        {
+            let mut buf = self.state.tx.buf.borrow_mut();
+            buf[..HEADER_LEN - 1].copy_from_slice(&(len as u32 - 1).to_le_bytes()[..HEADER_LEN - 1]);
+            trace!("bt tx {:02x}", Bytes(&buf[HEADER_LEN - 1..][..len.min(48)]));
+            ring_write(bus, self.addrs.host2bt_buf, self.host2bt_in, &buf[..frame_len]).await;
+        }
+
+        self.host2bt_in = (self.host2bt_in + frame_len as u32) % BTSDIO_FWBUF_SIZE;
+        bus.bp_write32(self.addrs.host2bt_in, self.host2bt_in).await;
+        self.toggle_intr(bus).await;
+        self.tx_done();
+    }
+
+    fn tx_done(&mut self) {
+        self.state.tx.len.set(0);
+        self.state.wakers.borrow_mut().tx.wake();
+    }
+
+    /// Read one packet from the controller. Returns false if there was none, or the RX slot is full.
+    async fn receive<PWR: OutputPin, T: Transport>(&mut self, bus: &mut Bus<PWR, T>) -> bool {
+        self.rx_stalled = self.state.rx.len.get() != 0;
+        if self.rx_stalled {
+            return false;
+        }
+
+        let bt2host_in = bus.bp_read32(self.addrs.bt2host_in).await;
+        if bt2host_in == self.bt2host_out {
+            return false;
+        }
+
+        let mut buf = self.state.rx.buf.borrow_mut();
+        ring_read(bus, self.addrs.bt2host_buf, self.bt2host_out, &mut buf[..HEADER_LEN]).await;
+        let payload_len = u32::from_le_bytes([buf[0], buf[1], buf[2], 0]) as usize;
+        let frame_len = (HEADER_LEN + payload_len + 3) & !3;
+
+        if 1 + payload_len > BT_MAX_PACKET_LEN || frame_len as u32 >= BTSDIO_FWBUF_SIZE {
+            warn!("bt rx packet too big, len={}, dropping", payload_len);
+        } else {
+            let offs = (self.bt2host_out + HEADER_LEN as u32) % BTSDIO_FWBUF_SIZE;
+            ring_read(bus, self.addrs.bt2host_buf, offs, &mut buf[HEADER_LEN..frame_len]).await;
+            trace!(
+                "bt rx {:02x}",
+                Bytes(&buf[HEADER_LEN - 1..][..(1 + payload_len).min(48)])
+            );
+            self.state.rx.len.set(1 + payload_len);
+            self.state.wakers.borrow_mut().rx.wake();
+        }
+        drop(buf);
+
+        self.bt2host_out = (self.bt2host_out + frame_len as u32) % BTSDIO_FWBUF_SIZE;
+        bus.bp_write32(self.addrs.bt2host_out, self.bt2host_out).await;
+        self.toggle_intr(bus).await;
+        true
+    }
+
+    /// Tell the controller the buffer indices changed.
+    async fn toggle_intr<PWR: OutputPin, T: Transport>(&mut self, bus: &mut Bus<PWR, T>) {
+        let ctrl = bus.bp_read32(HOST_CTRL_REG_ADDR).await;
+        bus.bp_write32(HOST_CTRL_REG_ADDR, ctrl ^ BTSDIO_REG_DATA_VALID).await;
+    }
+}
+
+/// Write `data` to the backplane at an address that may not be word-aligned.
+async fn write_unaligned<PWR: OutputPin, T: Transport>(bus: &mut Bus<PWR, T>, mut addr: u32, mut data: &[u8]) {
+    while addr % 4 != 0 && !data.is_empty() {
+        bus.bp_write8(addr, data[0]).await;
+        addr += 1;
+        data = &data[1..];
+    }
+    if !data.is_empty() {
+        bus.bp_write(addr, data).await;
+    }
+}
+
+/// Read `data` from a ring buffer at `buf`, starting at `offs` and wrapping around at its end.
+async fn ring_read<PWR: OutputPin, T: Transport>(bus: &mut Bus<PWR, T>, buf: u32, offs: u32, data: &mut [u8]) {
+    let first = data.len().min((BTSDIO_FWBUF_SIZE - offs) as usize);
+    let (head, tail) = data.split_at_mut(first);
+    bus.bp_read(buf + offs, head).await;
+    if !tail.is_empty() {
+        bus.bp_read(buf, tail).await;
+    }
+}
+
+/// Write `data` to a ring buffer at `buf`, starting at `offs` and wrapping around at its end.
+async fn ring_write<PWR: OutputPin, T: Transport>(bus: &mut Bus<PWR, T>, buf: u32, offs: u32, data: &[u8]) {
+    let first = data.len().min((BTSDIO_FWBUF_SIZE - offs) as usize);
+    let (head, tail) = data.split_at(first);
+    bus.bp_write(buf + offs, head).await;
+    if !tail.is_empty() {
+        bus.bp_write(buf, tail).await;
+    }
+}
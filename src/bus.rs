@@ -1,109 +1,181 @@
 use core::slice;
 
-use embassy_futures::yield_now;
 use embassy_time::{Duration, Timer};
 use embedded_hal_1::digital::OutputPin;
-use futures::FutureExt;
 
+use crate::chip::Chip;
 use crate::consts::*;
+use crate::InitError;
 
-/// Custom Spi Trait that _only_ supports the bus operation of the cyw43
-/// Implementors are expected to hold the CS pin low during an operation.
-pub trait SpiBusCyw43 {
-    /// Issues a write command on the bus
-    /// First 32 bits of `word` are expected to be a cmd word
-    async fn cmd_write(&mut self, write: &[u32]) -> u32;
-
-    /// Issues a read command on the bus
-    /// `write` is expected to be a 32 bit cmd word
-    /// `read` will contain the response of the device
-    /// Backplane reads have a response delay that produces one extra unspecified word at the beginning of `read`.
-    /// Callers that want to read `n` word from the backplane, have to provide a slice that is `n+1` words long.
-    async fn cmd_read(&mut self, write: u32, read: &mut [u32]) -> u32;
-
-    /// Wait for events from the Device. A typical implementation would wait for the IRQ pin to be high.
-    /// The default implementation always reports ready, resulting in active polling of the device.
-    async fn wait_for_event(&mut self) {
-        yield_now().await;
+pub(crate) mod sealed {
+    use super::Transport;
+
+    pub trait BusHost {
+        type Transport: Transport;
+
+        fn into_transport(self) -> Self::Transport;
     }
 }
 
-pub(crate) struct Bus<PWR, SPI> {
+/// Host side of the bus to the chip, either a [`SpiBusCyw43`](crate::SpiBusCyw43) for gSPI or an
+/// [`Sdio`](crate::Sdio) host. It is sealed, the bus protocols are internal to this crate.
+pub trait BusHost: sealed::BusHost {}
+
+/// Bus protocol below [`Bus`]. Functions are numbered like SDIO functions: 0 is the bus itself,
+/// 1 the backplane and 2 the WLAN frame channel.
+///
+/// Public only because [`sealed::BusHost`] names it. The module is private, so it can't be used or
+/// implemented outside this crate.
+pub trait Transport: Sized {
+    /// Bring up the bus after the chip has been powered on.
+    async fn init(&mut self) -> Result<(), InitError>;
+
+    /// Read a 1, 2 or 4 byte register of function 0 or 1.
+    async fn read_reg(&mut self, func: u32, addr: u32, len: u32) -> u32;
+
+    /// Write a 1, 2 or 4 byte register of function 0 or 1.
+    async fn write_reg(&mut self, func: u32, addr: u32, val: u32, len: u32);
+
+    /// Read `data.len()` bytes, at most [`BACKPLANE_MAX_TRANSFER_SIZE`], from backplane window offset `addr`.
+    async fn bp_read(&mut self, addr: u32, data: &mut [u8]);
+
+    /// Write `data.len()` bytes, at most [`BACKPLANE_MAX_TRANSFER_SIZE`], to backplane window offset `addr`.
+    async fn bp_write(&mut self, addr: u32, data: &[u8]);
+
+    /// Read the pending bus interrupts, as `IRQ_*` bits.
+    async fn interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip) -> u16;
+
+    /// Clear the bus interrupts in `irq`.
+    async fn clear_interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip, irq: u16);
+
+    /// Enable the bus interrupts in `irq`, and disable all others.
+    async fn enable_interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip, irq: u16);
+
+    /// Whether the firmware is ready to exchange frames on function 2.
+    async fn wlan_ready(&mut self) -> bool;
+
+    /// Length of the next frame waiting on function 2, if any.
+    async fn wlan_pending(&mut self) -> Option<u32>;
+
+    /// Read the frame announced by [`wlan_pending`](Self::wlan_pending), `len` bytes, into `buf`.
+    async fn wlan_read(&mut self, buf: &mut [u32], len: u32);
+
+    /// Drop the rest of the frame currently being received on function 2.
+    async fn wlan_drop(&mut self);
+
+    /// Write a frame of `len` bytes to function 2, made of the words of every segment in `data`.
+    async fn wlan_write(&mut self, data: &[&[u32]], len: u32);
+
+    /// Wait for an interrupt from the chip.
+    async fn wait_for_event(&mut self);
+}
+
+/// Counters of bus errors reported by the chip, see [`Control::bus_errors`](crate::Control::bus_errors).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BusErrors {
+    /// F2 reads of data the chip didn't have.
+    pub data_unavailable: u32,
+    /// F2/F3 reads past the end of the frame in the chip's FIFO.
+    pub fifo_underflow: u32,
+    /// F2/F3 writes the chip had no room for. The frame is dropped.
+    pub fifo_overflow: u32,
+    /// Commands the chip could not decode.
+    pub command_error: u32,
+    /// Transfers with corrupted data.
+    pub data_error: u32,
+    /// Backplane writes issued while the previous one was still pending.
+    pub f1_overflow: u32,
+    /// Received frames whose SDPCM sequence number was not the expected one.
+    pub rx_seq_mismatch: u32,
+}
+
+pub struct Bus<PWR, T> {
     backplane_window: u32,
     pwr: PWR,
-    spi: SPI,
-    status: u32,
+    transport: T,
 }
 
-impl<PWR, SPI> Bus<PWR, SPI>
+impl<PWR, T> Bus<PWR, T>
 where
     PWR: OutputPin,
-    SPI: SpiBusCyw43,
+    T: Transport,
 {
-    pub(crate) fn new(pwr: PWR, spi: SPI) -> Self {
+    pub(crate) fn new(pwr: PWR, transport: T) -> Self {
         Self {
             backplane_window: 0xAAAA_AAAA,
             pwr,
-            spi,
-            status: 0,
+            transport,
         }
     }
 
-    pub async fn init(&mut self) {
+    pub(crate) fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    pub async fn init(&mut self) -> Result<(), InitError> {
         // Reset
         self.pwr.set_low().unwrap();
         Timer::after(Duration::from_millis(20)).await;
         self.pwr.set_high().unwrap();
         Timer::after(Duration::from_millis(250)).await;
 
-        while self
-            .read32_swapped(REG_BUS_TEST_RO)
-            .inspect(|v| trace!("{:#x}", v))
-            .await
-            != FEEDBEAD
-        {}
-
-        self.write32_swapped(REG_BUS_TEST_RW, TEST_PATTERN).await;
-        let val = self.read32_swapped(REG_BUS_TEST_RW).await;
-        trace!("{:#x}", val);
-        assert_eq!(val, TEST_PATTERN);
-
-        let val = self.read32_swapped(REG_BUS_CTRL).await;
-        trace!("{:#010b}", (val & 0xff));
-
-        // 32-bit word length, little endian (which is the default endianess).
-        self.write32_swapped(
-            REG_BUS_CTRL,
-            WORD_LENGTH_32 | HIGH_SPEED | INTERRUPT_HIGH | WAKE_UP | STATUS_ENABLE | INTERRUPT_WITH_STATUS,
-        )
-        .await;
-
-        let val = self.read8(FUNC_BUS, REG_BUS_CTRL).await;
-        trace!("{:#b}", val);
-
-        let val = self.read32(FUNC_BUS, REG_BUS_TEST_RO).await;
-        trace!("{:#x}", val);
-        assert_eq!(val, FEEDBEAD);
-        let val = self.read32(FUNC_BUS, REG_BUS_TEST_RW).await;
-        trace!("{:#x}", val);
-        assert_eq!(val, TEST_PATTERN);
+        // The window is lost with the power cycle.
+        self.backplane_window = 0xAAAA_AAAA;
+
+        self.transport.init().await
+    }
+
+    pub async fn interrupts(&mut self, chip: &Chip) -> u16 {
+        T::interrupts(self, chip).await
+    }
+
+    pub async fn clear_interrupts(&mut self, chip: &Chip, irq: u16) {
+        T::clear_interrupts(self, chip, irq).await
+    }
+
+    pub async fn enable_interrupts(&mut self, chip: &Chip, irq: u16) {
+        T::enable_interrupts(self, chip, irq).await
+    }
+
+    pub async fn wlan_ready(&mut self) -> bool {
+        self.transport.wlan_ready().await
+    }
+
+    pub async fn wlan_pending(&mut self) -> Option<u32> {
+        self.transport.wlan_pending().await
     }
 
     pub async fn wlan_read(&mut self, buf: &mut [u32], len_in_u8: u32) {
-        let cmd = cmd_word(READ, INC_ADDR, FUNC_WLAN, 0, len_in_u8);
-        let len_in_u32 = (len_in_u8 as usize + 3) / 4;
+        self.transport.wlan_read(buf, len_in_u8).await
+    }
 
-        self.status = self.spi.cmd_read(cmd, &mut buf[..len_in_u32]).await;
+    pub async fn wlan_drop(&mut self) {
+        self.transport.wlan_drop().await
     }
 
-    pub async fn wlan_write(&mut self, buf: &[u32]) {
-        let cmd = cmd_word(WRITE, INC_ADDR, FUNC_WLAN, 0, buf.len() as u32 * 4);
-        //TODO try to remove copy?
-        let mut cmd_buf = [0_u32; 513];
-        cmd_buf[0] = cmd;
-        cmd_buf[1..][..buf.len()].copy_from_slice(buf);
+    /// Write an F2 frame consisting of `header` followed by `payload`, padded to a word boundary.
+    /// `payload` is sent in place if it is word-aligned, otherwise it is copied to `scratch` first.
+    pub async fn wlan_write(&mut self, header: &[u32], payload: &[u8], scratch: &mut [u32]) {
+        let len = (header.len() * 4 + payload.len() + 3) & !3;
 
-        self.status = self.spi.cmd_write(&cmd_buf).await;
+        // Safety: every bit pattern is a valid u32.
+        let (prefix, words, suffix) = unsafe { payload.align_to::<u32>() };
+        let (words, suffix) = if prefix.is_empty() {
+            (words, suffix)
+        } else {
+            let n = payload.len() / 4;
+            slice8_mut(&mut scratch[..n]).copy_from_slice(&payload[..n * 4]);
+            (&scratch[..n], &payload[n * 4..])
+        };
+
+        // The last partial word is padded with zeros.
+        let mut tail = [0; 4];
+        tail[..suffix.len()].copy_from_slice(suffix);
+        let tail = [u32::from_ne_bytes(tail)];
+        let tail: &[u32] = if suffix.is_empty() { &[] } else { &tail };
+
+        self.transport.wlan_write(&[header, words, tail], len as u32).await
     }
 
     #[allow(unused)]
@@ -114,9 +186,6 @@ where
         // To simplify, enforce 4-align for now.
         assert!(addr % 4 == 0);
 
-        // Backplane read buffer has one extra word for the response delay.
-        let mut buf = [0u32; BACKPLANE_MAX_TRANSFER_SIZE / 4 + 1];
-
         while !data.is_empty() {
             // Ensure transfer doesn't cross a window boundary.
             let window_offs = addr & BACKPLANE_ADDRESS_MASK;
@@ -126,13 +195,7 @@ where
 
             self.backplane_set_window(addr).await;
 
-            let cmd = cmd_word(READ, INC_ADDR, FUNC_BACKPLANE, window_offs, len as u32);
-
-            // round `buf` to word boundary, add one extra word for the response delay
-            self.status = self.spi.cmd_read(cmd, &mut buf[..(len + 3) / 4 + 1]).await;
-
-            // when writing out the data, we skip the response-delay byte
-            data[..len].copy_from_slice(&slice8_mut(&mut buf[1..])[..len]);
+            self.transport.bp_read(window_offs, &mut data[..len]).await;
 
             // Advance ptr.
             addr += len as u32;
@@ -147,22 +210,16 @@ where
         // To simplify, enforce 4-align for now.
         assert!(addr % 4 == 0);
 
-        let mut buf = [0u32; BACKPLANE_MAX_TRANSFER_SIZE / 4 + 1];
-
         while !data.is_empty() {
             // Ensure transfer doesn't cross a window boundary.
             let window_offs = addr & BACKPLANE_ADDRESS_MASK;
             let window_remaining = BACKPLANE_WINDOW_SIZE - window_offs as usize;
 
             let len = data.len().min(BACKPLANE_MAX_TRANSFER_SIZE).min(window_remaining);
-            slice8_mut(&mut buf[1..])[..len].copy_from_slice(&data[..len]);
 
             self.backplane_set_window(addr).await;
 
-            let cmd = cmd_word(WRITE, INC_ADDR, FUNC_BACKPLANE, window_offs, len as u32);
-            buf[0] = cmd;
-
-            self.status = self.spi.cmd_write(&buf[..(len + 3) / 4 + 1]).await;
+            self.transport.bp_write(window_offs, &data[..len]).await;
 
             // Advance ptr.
             addr += len as u32;
@@ -178,6 +235,7 @@ where
         self.backplane_writen(addr, val as u32, 1).await
     }
 
+    #[allow(unused)]
     pub async fn bp_read16(&mut self, addr: u32) -> u16 {
         self.backplane_readn(addr, 2).await as u16
     }
@@ -187,7 +245,6 @@ where
         self.backplane_writen(addr, val as u32, 2).await
     }
 
-    #[allow(unused)]
     pub async fn bp_read32(&mut self, addr: u32) -> u32 {
         self.backplane_readn(addr, 4).await
     }
@@ -203,7 +260,7 @@ where
         if len == 4 {
             bus_addr |= BACKPLANE_ADDRESS_32BIT_FLAG
         }
-        self.readn(FUNC_BACKPLANE, bus_addr, len).await
+        self.transport.read_reg(FUNC_BACKPLANE, bus_addr, len).await
     }
 
     async fn backplane_writen(&mut self, addr: u32, val: u32, len: u32) {
@@ -213,7 +270,7 @@ where
         if len == 4 {
             bus_addr |= BACKPLANE_ADDRESS_32BIT_FLAG
         }
-        self.writen(FUNC_BACKPLANE, bus_addr, val, len).await
+        self.transport.write_reg(FUNC_BACKPLANE, bus_addr, val, len).await
     }
 
     async fn backplane_set_window(&mut self, addr: u32) {
@@ -247,87 +304,37 @@ where
     }
 
     pub async fn read8(&mut self, func: u32, addr: u32) -> u8 {
-        self.readn(func, addr, 1).await as u8
+        self.transport.read_reg(func, addr, 1).await as u8
     }
 
     pub async fn write8(&mut self, func: u32, addr: u32, val: u8) {
-        self.writen(func, addr, val as u32, 1).await
+        self.transport.write_reg(func, addr, val as u32, 1).await
     }
 
     pub async fn read16(&mut self, func: u32, addr: u32) -> u16 {
-        self.readn(func, addr, 2).await as u16
+        self.transport.read_reg(func, addr, 2).await as u16
     }
 
     #[allow(unused)]
     pub async fn write16(&mut self, func: u32, addr: u32, val: u16) {
-        self.writen(func, addr, val as u32, 2).await
+        self.transport.write_reg(func, addr, val as u32, 2).await
     }
 
+    #[allow(unused)]
     pub async fn read32(&mut self, func: u32, addr: u32) -> u32 {
-        self.readn(func, addr, 4).await
+        self.transport.read_reg(func, addr, 4).await
     }
 
     #[allow(unused)]
     pub async fn write32(&mut self, func: u32, addr: u32, val: u32) {
-        self.writen(func, addr, val, 4).await
-    }
-
-    async fn readn(&mut self, func: u32, addr: u32, len: u32) -> u32 {
-        let cmd = cmd_word(READ, INC_ADDR, func, addr, len);
-        let mut buf = [0; 2];
-        // if we are reading from the backplane, we need an extra word for the response delay
-        let len = if func == FUNC_BACKPLANE { 2 } else { 1 };
-
-        self.status = self.spi.cmd_read(cmd, &mut buf[..len]).await;
-
-        // if we read from the backplane, the result is in the second word, after the response delay
-        if func == FUNC_BACKPLANE {
-            buf[1]
-        } else {
-            buf[0]
-        }
-    }
-
-    async fn writen(&mut self, func: u32, addr: u32, val: u32, len: u32) {
-        let cmd = cmd_word(WRITE, INC_ADDR, func, addr, len);
-
-        self.status = self.spi.cmd_write(&[cmd, val]).await;
-    }
-
-    async fn read32_swapped(&mut self, addr: u32) -> u32 {
-        let cmd = cmd_word(READ, INC_ADDR, FUNC_BUS, addr, 4);
-        let cmd = swap16(cmd);
-        let mut buf = [0; 1];
-
-        self.status = self.spi.cmd_read(cmd, &mut buf).await;
-
-        swap16(buf[0])
-    }
-
-    async fn write32_swapped(&mut self, addr: u32, val: u32) {
-        let cmd = cmd_word(WRITE, INC_ADDR, FUNC_BUS, addr, 4);
-        let buf = [swap16(cmd), swap16(val)];
-
-        self.status = self.spi.cmd_write(&buf).await;
+        self.transport.write_reg(func, addr, val, 4).await
     }
 
     pub async fn wait_for_event(&mut self) {
-        self.spi.wait_for_event().await;
-    }
-
-    pub fn status(&self) -> u32 {
-        self.status
+        self.transport.wait_for_event().await;
     }
 }
 
-fn swap16(x: u32) -> u32 {
-    x.rotate_left(16)
-}
-
-fn cmd_word(write: bool, incr: bool, func: u32, addr: u32, len: u32) -> u32 {
-    (write as u32) << 31 | (incr as u32) << 30 | (func & 0b11) << 28 | (addr & 0x1FFFF) << 11 | (len & 0x7FF)
-}
-
 fn slice8_mut(x: &mut [u32]) -> &mut [u8] {
     let len = x.len() * 4;
     unsafe { slice::from_raw_parts_mut(x.as_mut_ptr() as _, len) }
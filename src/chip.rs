@@ -0,0 +1,176 @@
+#[allow(unused)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Core {
+    WLAN = 0,
+    SOCSRAM = 1,
+    SDIOD = 2,
+}
+
+impl Core {
+    pub(crate) fn base_addr(&self, chip: &Chip) -> u32 {
+        match self {
+            Self::WLAN => chip.arm_core_base_address,
+            Self::SOCSRAM => chip.socsram_wrapper_base_address,
+            Self::SDIOD => chip.sdiod_core_base_address,
+        }
+    }
+}
+
+/// CPU core running the WLAN firmware.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ArmCore {
+    /// Cortex-M3, running from the SOCSRAM core's RAM.
+    CM3,
+    /// Cortex-R4, running from its own TCM. Starts at the reset vector stored at address 0.
+    CR4,
+}
+
+#[allow(unused)]
+pub struct Chip {
+    /// Chip ID as read from the ChipCommon core.
+    pub(crate) id: u16,
+    /// Supported chip revisions, bit `n` is set if revision `n` is. Revisions of the same chip ID can
+    /// need different firmware and layouts, they get separate entries.
+    pub(crate) revisions: u16,
+    /// Chip name as it appears in the version string of its firmware, e.g. `43439a0-roml/...`.
+    pub(crate) firmware_name: &'static [u8],
+    pub(crate) arm_core: ArmCore,
+    pub(crate) arm_core_base_address: u32,
+    pub(crate) socsram_base_address: u32,
+    pub(crate) socsram_wrapper_base_address: u32,
+    pub(crate) sdiod_core_base_address: u32,
+    pub(crate) pmu_base_address: u32,
+    pub(crate) chip_ram_size: u32,
+    pub(crate) atcm_ram_base_address: u32,
+    pub(crate) socram_srmem_size: u32,
+    /// Base address of the Bluetooth core's memory, for chips with Bluetooth on the shared bus.
+    pub(crate) bluetooth_base_address: Option<u32>,
+    pub(crate) chanspec_band_mask: u32,
+    pub(crate) chanspec_band_2g: u32,
+    pub(crate) chanspec_band_5g: u32,
+    pub(crate) chanspec_band_shift: u32,
+    pub(crate) chanspec_bw_10: u32,
+    pub(crate) chanspec_bw_20: u32,
+    pub(crate) chanspec_bw_40: u32,
+    pub(crate) chanspec_bw_mask: u32,
+    pub(crate) chanspec_bw_shift: u32,
+    pub(crate) chanspec_ctl_sb_lower: u32,
+    pub(crate) chanspec_ctl_sb_upper: u32,
+    pub(crate) chanspec_ctl_sb_none: u32,
+    pub(crate) chanspec_ctl_sb_mask: u32,
+}
+
+/// Address of the ChipCommon chip ID register. Bits 0-15 hold the chip ID, bits 16-19 the revision.
+pub(crate) const CHIPCOMMON_CHIP_ID_ADDRESS: u32 = 0x1800_0000;
+
+const WRAPPER_REGISTER_OFFSET: u32 = 0x100000;
+
+pub(crate) const CYW43439: Chip = Chip {
+    id: 43439,
+    revisions: 0xffff,
+    firmware_name: b"43439",
+    arm_core: ArmCore::CM3,
+    arm_core_base_address: 0x18003000 + WRAPPER_REGISTER_OFFSET,
+    socsram_base_address: 0x18004000,
+    socsram_wrapper_base_address: 0x18004000 + WRAPPER_REGISTER_OFFSET,
+    sdiod_core_base_address: 0x18002000,
+    pmu_base_address: 0x18000000,
+    chip_ram_size: 512 * 1024,
+    atcm_ram_base_address: 0,
+    socram_srmem_size: 64 * 1024,
+    bluetooth_base_address: Some(0x1900_0000),
+    chanspec_band_mask: 0xc000,
+    chanspec_band_2g: 0x0000,
+    chanspec_band_5g: 0xc000,
+    chanspec_band_shift: 14,
+    chanspec_bw_10: 0x0800,
+    chanspec_bw_20: 0x1000,
+    chanspec_bw_40: 0x1800,
+    chanspec_bw_mask: 0x3800,
+    chanspec_bw_shift: 11,
+    chanspec_ctl_sb_lower: 0x0000,
+    chanspec_ctl_sb_upper: 0x0100,
+    chanspec_ctl_sb_none: 0x0000,
+    chanspec_ctl_sb_mask: 0x0700,
+};
+
+/// CYW4343W, reports itself as 43430 revision 1 and up. Revision 0 is the unsupported 43430A0.
+const CYW4343W: Chip = Chip {
+    id: 43430,
+    revisions: 0xfffe,
+    firmware_name: b"43430",
+    // Bluetooth is on UART.
+    bluetooth_base_address: None,
+    ..CYW43439
+};
+
+/// CYW43455, reports itself as 0x4345 revision 6 and up. Earlier revisions are other chips.
+const CYW43455: Chip = Chip {
+    id: 0x4345,
+    revisions: 0xffc0,
+    firmware_name: b"43455",
+    arm_core: ArmCore::CR4,
+    arm_core_base_address: 0x18002000 + WRAPPER_REGISTER_OFFSET,
+    // No SOCSRAM core, the RAM is the CR4's TCM.
+    socsram_base_address: 0,
+    socsram_wrapper_base_address: 0,
+    sdiod_core_base_address: 0x18004000,
+    chip_ram_size: 800 * 1024,
+    atcm_ram_base_address: 0x198000,
+    socram_srmem_size: 0,
+    bluetooth_base_address: None,
+    ..CYW43439
+};
+
+/// Supported chips.
+static CHIPS: &[Chip] = &[CYW43439, CYW4343W, CYW43455];
+
+impl Chip {
+    /// Look up the chip with the given ID and revision.
+    pub(crate) fn from_id(id: u16, rev: u8) -> Option<&'static Chip> {
+        CHIPS
+            .iter()
+            .find(|chip| chip.id == id && rev < 16 && chip.revisions & (1 << rev) != 0)
+    }
+
+    /// Address of the word after the end of RAM, where the firmware looks for the NVRAM.
+    fn ram_end(&self) -> u32 {
+        self.atcm_ram_base_address + self.chip_ram_size
+    }
+
+    /// Address of the NVRAM length token, the last word of RAM.
+    pub(crate) fn nvram_token_address(&self) -> u32 {
+        self.ram_end() - 4
+    }
+
+    /// Address to write `len` bytes of NVRAM to, right below the length token. `len` is a multiple of 4.
+    pub(crate) fn nvram_address(&self, len: usize) -> u32 {
+        self.nvram_token_address() - len as u32
+    }
+
+    /// Address of the word holding the address of the firmware's shared memory area, below the
+    /// save/restore memory.
+    pub(crate) fn shared_pointer_address(&self) -> u32 {
+        self.ram_end() - 4 - self.socram_srmem_size
+    }
+
+    /// Check `firmware` against this chip, by the chip name in the firmware's version string.
+    ///
+    /// Returns `None` if the firmware has no recognizable version string.
+    pub(crate) fn matches_firmware(&self, firmware: &[u8]) -> Option<bool> {
+        // The version string is in the last few hundred bytes, e.g. `43439a0-roml/...`.
+        const MARKER: &[u8] = b"-roml/";
+        let tail = &firmware[firmware.len().saturating_sub(1024)..];
+        let pos = tail.windows(MARKER.len()).position(|w| w == MARKER)?;
+
+        // Chip name and revision, e.g. `43439a0`.
+        let start = tail[..pos]
+            .iter()
+            .rposition(|b| !b.is_ascii_alphanumeric())
+            .map_or(0, |i| i + 1);
+        let name = &tail[start..pos];
+        let digits = name.iter().take_while(|b| b.is_ascii_digit()).count();
+
+        Some(&name[..digits] == self.firmware_name)
+    }
+}
@@ -0,0 +1,51 @@
+/// Wi-Fi/Bluetooth coexistence mode, the firmware's `btc_mode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CoexMode {
+    /// No coexistence. Use this while Bluetooth is off, Wi-Fi then never yields the antenna.
+    Disabled = 0,
+    /// Wi-Fi and Bluetooth take turns on the antenna. This is the default set by the NVRAM.
+    FullTdm = 1,
+    /// Like `FullTdm`, but Bluetooth may preempt Wi-Fi for high priority traffic.
+    Preemption = 2,
+    /// Lightweight coexistence, for boards with good isolation between the antennas.
+    Lite = 3,
+    /// Wi-Fi and Bluetooth run in parallel, on separate antennas.
+    Parallel = 4,
+    /// Like `FullTdm`, but Wi-Fi may still send ACKs during Bluetooth slots.
+    Hybrid = 5,
+}
+
+impl CoexMode {
+    pub(crate) fn from_u32(mode: u32) -> Option<Self> {
+        match mode {
+            0 => Some(Self::Disabled),
+            1 => Some(Self::FullTdm),
+            2 => Some(Self::Preemption),
+            3 => Some(Self::Lite),
+            4 => Some(Self::Parallel),
+            5 => Some(Self::Hybrid),
+            _ => None,
+        }
+    }
+}
+
+/// Coexistence settings, see [`Control::set_coex`](crate::Control::set_coex).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CoexConfig<'a> {
+    pub mode: CoexMode,
+    /// Coexistence parameters to set, as `(index, value)` pairs of the firmware's `btc_params`.
+    /// These hold the arbitration timing, e.g. the WLAN and Bluetooth priority windows. Indices
+    /// and units depend on the firmware build, parameters not listed keep their current value.
+    pub params: &'a [(u32, u32)],
+}
+
+impl Default for CoexConfig<'_> {
+    fn default() -> Self {
+        Self {
+            mode: CoexMode::FullTdm,
+            params: &[],
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Decompression of firmware and CLM blobs compressed with `cyw43-compress`.
+//!
+//! The format is LZSS with a 2 KiB window:
+//!
+//! - Header: the magic `CYWZ`, then the decompressed length as a little endian `u32`.
+//! - Data: groups of a flag byte followed by up to 8 items, one per flag bit starting with the
+//!   least significant one. A 1 bit is a literal byte. A 0 bit is a match of 2 bytes, a little
+//!   endian `u16` holding the distance minus 1 in bits 0-10 and the length minus 3 in bits 11-15.
+//!
+//! The data ends when the decompressed length is reached.
+
+use core::cmp::min;
+
+use crate::firmware::{FirmwareReadError, FirmwareSource};
+use crate::{ClmError, InitError};
+
+/// Magic at the start of a compressed blob.
+pub(crate) const MAGIC: &[u8; 4] = b"CYWZ";
+
+const HEADER_LEN: usize = 8;
+const WINDOW_SIZE: usize = 2048;
+const MIN_MATCH: usize = 3;
+
+/// Compressed input is read from the source in pieces of this size.
+const INPUT_BUF_SIZE: usize = 64;
+
+/// Most input bytes a single item needs: a flag byte and a match.
+const MAX_ITEM_LEN: usize = 3;
+
+/// Size of the chunks handed out by [`Decompressor::next_chunk`]. Matches the CLM download chunk size.
+pub(crate) const CHUNK_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum Error {
+    /// The compressed data is truncated or refers to data before its start.
+    Corrupt,
+    Read(FirmwareReadError),
+}
+
+impl From<FirmwareReadError> for Error {
+    fn from(e: FirmwareReadError) -> Self {
+        Self::Read(e)
+    }
+}
+
+impl From<Error> for ClmError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Corrupt => ClmError::Corrupt,
+            Error::Read(e) => e.into(),
+        }
+    }
+}
+
+impl From<Error> for InitError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Corrupt => InitError::FirmwareCorrupt,
+            Error::Read(e) => e.into(),
+        }
+    }
+}
+
+pub(crate) async fn is_compressed<S: FirmwareSource>(src: &mut S) -> Result<bool, FirmwareReadError> {
+    if src.len() < 4 {
+        return Ok(false);
+    }
+    let mut magic = [0; 4];
+    src.read(0, &mut magic).await?;
+    Ok(&magic == MAGIC)
+}
+
+/// Streaming decompressor. Output is produced in chunks that live in the window, so nothing but
+/// the window and a small input buffer is buffered.
+pub(crate) struct Decompressor<'a, S> {
+    src: &'a mut S,
+    /// Offset in `src` of the next input to read.
+    src_offs: usize,
+    input: [u8; INPUT_BUF_SIZE],
+    input_pos: usize,
+    input_len: usize,
+    window: [u8; WINDOW_SIZE],
+    len: usize,
+    /// Decompressed bytes produced so far.
+    out: usize,
+    /// Flag bits of the current group, above a sentinel bit. 1 when a new flag byte is needed.
+    flags: u16,
+    /// Distance and remaining length of the match being copied.
+    copy: (usize, usize),
+}
+
+impl<'a, S: FirmwareSource> Decompressor<'a, S> {
+    pub(crate) async fn new(src: &'a mut S) -> Result<Decompressor<'a, S>, Error> {
+        if src.len() < HEADER_LEN {
+            return Err(Error::Corrupt);
+        }
+        let mut header = [0; HEADER_LEN];
+        src.read(0, &mut header).await?;
+        if &header[..4] != MAGIC {
+            return Err(Error::Corrupt);
+        }
+        let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+
+        Ok(Self {
+            src,
+            src_offs: HEADER_LEN,
+            input: [0; INPUT_BUF_SIZE],
+            input_pos: 0,
+            input_len: 0,
+            window: [0; WINDOW_SIZE],
+            len,
+            out: 0,
+            flags: 1,
+            copy: (0, 0),
+        })
+    }
+
+    /// Decompressed length.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Decompress the next [`CHUNK_SIZE`] bytes, fewer for the last chunk. Returns `None` at the end.
+    pub(crate) async fn next_chunk(&mut self) -> Result<Option<&[u8]>, Error> {
+        if self.out == self.len {
+            return Ok(None);
+        }
+
+        // Chunks start at multiples of CHUNK_SIZE, so each one is contiguous in the window.
+        let start = self.out;
+        let end = min(self.len, start + CHUNK_SIZE);
+        while self.out < end {
+            if self.copy.1 > 0 {
+                let (dist, len) = self.copy;
+                let b = self.window[(self.out - dist) % WINDOW_SIZE];
+                self.put(b);
+                self.copy.1 = len - 1;
+                continue;
+            }
+
+            if self.input_len - self.input_pos < MAX_ITEM_LEN && self.src_offs < self.src.len() {
+                self.refill().await?;
+            }
+            if self.flags == 1 {
+                self.flags = 0x100 | self.byte()? as u16;
+            }
+            let literal = self.flags & 1 != 0;
+            self.flags >>= 1;
+
+            if literal {
+                let b = self.byte()?;
+                self.put(b);
+            } else {
+                let item = u16::from_le_bytes([self.byte()?, self.byte()?]) as usize;
+                let dist = (item & 0x7ff) + 1;
+                if dist > self.out {
+                    return Err(Error::Corrupt);
+                }
+                self.copy = (dist, (item >> 11) + MIN_MATCH);
+            }
+        }
+
+        Ok(Some(&self.window[start % WINDOW_SIZE..][..end - start]))
+    }
+
+    /// The last decompressed bytes, up to the window size. Only valid once all chunks were read.
+    pub(crate) fn tail(&mut self) -> &[u8] {
+        self.window.rotate_left(self.out % WINDOW_SIZE);
+        &self.window[WINDOW_SIZE - min(self.out, WINDOW_SIZE)..]
+    }
+
+    /// Move the unread input to the start of the buffer, and fill the rest from the source.
+    async fn refill(&mut self) -> Result<(), Error> {
+        self.input.copy_within(self.input_pos..self.input_len, 0);
+        self.input_len -= self.input_pos;
+        self.input_pos = 0;
+
+        let n = min(INPUT_BUF_SIZE - self.input_len, self.src.len() - self.src_offs);
+        self.src
+            .read(self.src_offs, &mut self.input[self.input_len..][..n])
+            .await?;
+        self.src_offs += n;
+        self.input_len += n;
+        Ok(())
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        if self.input_pos == self.input_len {
+            return Err(Error::Corrupt);
+        }
+        self.input_pos += 1;
+        Ok(self.input[self.input_pos - 1])
+    }
+
+    fn put(&mut self, b: u8) {
+        self.window[self.out % WINDOW_SIZE] = b;
+        self.out += 1;
+    }
+}
@@ -0,0 +1,108 @@
+use embassy_time::Duration;
+
+use crate::aggregation::AggregationConfig;
+use crate::antenna::Antenna;
+use crate::countries::{self, Country};
+use crate::events::Event;
+use crate::ioctl::IoctlError;
+use crate::phy::PhyConfig;
+use crate::ClmError;
+
+/// Events [`Config::default`] disables, which are frequent and not used by this driver.
+pub const DEFAULT_DISABLED_EVENTS: &[Event] = &[
+    Event::RADIO,
+    Event::IF,
+    Event::PROBREQ_MSG,
+    Event::PROBREQ_MSG_RX,
+    Event::PROBRESP_MSG,
+    Event::ROAM,
+];
+
+/// Radio configuration applied by [`Control::init`](crate::Control::init).
+///
+/// `Default` is the configuration for the Raspberry Pi Pico W. Settings left at their defaults that
+/// the firmware starts with aren't sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Config {
+    /// Country whose regulatory rules apply, see [`countries`]. The worldwide default only uses
+    /// settings allowed everywhere.
+    pub country: Country,
+    /// Antenna to receive and transmit on, see [`Control::set_antenna`](crate::Control::set_antenna).
+    pub antenna: Antenna,
+    /// 802.11 modes, band and rates, see [`Control::set_phy`](crate::Control::set_phy).
+    pub phy: PhyConfig,
+    /// A-MPDU aggregation, see [`Control::set_aggregation`](crate::Control::set_aggregation).
+    pub aggregation: AggregationConfig,
+    /// Let the firmware send several packets to the host in one bus transfer, the firmware's `bus:txglom`.
+    pub tx_glom: bool,
+    /// Allow running an access point while connected to one, the firmware's `apsta`.
+    pub apsta: bool,
+    /// Events the firmware doesn't send.
+    pub disabled_events: &'static [Event],
+    /// How long to wait after settings the firmware applies in the background: the country, the
+    /// aggregation, the event mask, bringing the interface up and the PHY settings. Ioctls sent
+    /// before the firmware is done can fail.
+    pub settle_time: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            country: countries::WORLD_WIDE_XX,
+            antenna: Antenna::Chip,
+            phy: PhyConfig::default(),
+            aggregation: AggregationConfig::default(),
+            tx_glom: false,
+            apsta: true,
+            disabled_events: DEFAULT_DISABLED_EVENTS,
+            settle_time: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Step of [`Control::init`](crate::Control::init) after the CLM download, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InitStep {
+    /// Setting [`Config::tx_glom`].
+    TxGlom,
+    /// Setting [`Config::apsta`].
+    ApSta,
+    /// Reading the MAC address.
+    MacAddress,
+    /// Setting [`Config::country`].
+    Country,
+    /// Setting [`Config::antenna`].
+    Antenna,
+    /// Setting [`Config::aggregation`].
+    Aggregation,
+    /// Setting [`Config::disabled_events`].
+    EventMask,
+    /// Bringing the interface up.
+    Up,
+    /// Setting [`Config::phy`]. A non-default 802.11n mode is set before bringing the interface up.
+    Phy,
+}
+
+impl InitStep {
+    pub(crate) fn failed(self) -> impl FnOnce(IoctlError) -> ControlInitError {
+        move |e| ControlInitError::Step(self, e)
+    }
+}
+
+/// Error of [`Control::init`](crate::Control::init).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ControlInitError {
+    /// Downloading the CLM failed.
+    Clm(ClmError),
+    /// The firmware rejected a step of the configuration.
+    Step(InitStep, IoctlError),
+}
+
+impl From<ClmError> for ControlInitError {
+    fn from(e: ClmError) -> Self {
+        ControlInitError::Clm(e)
+    }
+}
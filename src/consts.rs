@@ -51,6 +51,9 @@ pub(crate) const REG_BACKPLANE_READ_FRAME_BC_HIGH: u32 = 0x1001C;
 pub(crate) const REG_BACKPLANE_WAKEUP_CTRL: u32 = 0x1001E;
 pub(crate) const REG_BACKPLANE_SLEEP_CSR: u32 = 0x1001F;
 
+// REG_BACKPLANE_FRAME_CONTROL bits
+pub(crate) const FRAME_CONTROL_RF_TERM: u8 = 0x01; // Read frame terminate, drops the pending F2 packet
+
 pub(crate) const BACKPLANE_WINDOW_SIZE: usize = 0x8000;
 pub(crate) const BACKPLANE_ADDRESS_MASK: u32 = 0x7FFF;
 pub(crate) const BACKPLANE_ADDRESS_32BIT_FLAG: u32 = 0x08000;
@@ -71,6 +74,10 @@ pub(crate) const AI_RESETCTRL_BIT_RESET: u8 = 1;
 
 pub(crate) const AI_RESETSTATUS_OFFSET: u32 = 0x804;
 
+// SharedMemData flags
+pub(crate) const SHARED_ASSERT: u32 = 0x0200;
+pub(crate) const SHARED_TRAP: u32 = 0x0400;
+
 pub(crate) const TEST_PATTERN: u32 = 0x12345678;
 pub(crate) const FEEDBEAD: u32 = 0xFEEDBEAD;
 
@@ -92,9 +99,27 @@ pub(crate) const IRQ_F1_INTR: u16 = 0x2000;
 pub(crate) const IRQ_F2_INTR: u16 = 0x4000;
 pub(crate) const IRQ_F3_INTR: u16 = 0x8000;
 
+/// Bus error interrupts. All of them are cleared by writing 1.
+pub(crate) const IRQ_BUS_ERRORS: u16 = IRQ_DATA_UNAVAILABLE
+    | IRQ_F2_F3_FIFO_RD_UNDERFLOW
+    | IRQ_F2_F3_FIFO_WR_OVERFLOW
+    | IRQ_COMMAND_ERROR
+    | IRQ_DATA_ERROR
+    | IRQ_F1_OVERFLOW;
+
 pub(crate) const IOCTL_CMD_UP: u32 = 2;
+pub(crate) const IOCTL_CMD_DOWN: u32 = 3;
 pub(crate) const IOCTL_CMD_SET_SSID: u32 = 26;
-pub(crate) const IOCTL_CMD_ANTDIV: u32 = 64;
+pub(crate) const IOCTL_CMD_GET_ANTDIV: u32 = 63;
+pub(crate) const IOCTL_CMD_SET_ANTDIV: u32 = 64;
+pub(crate) const IOCTL_CMD_GET_TXANT: u32 = 65;
+pub(crate) const IOCTL_CMD_SET_TXANT: u32 = 66;
+pub(crate) const IOCTL_CMD_GET_RATESET: u32 = 72;
+pub(crate) const IOCTL_CMD_SET_RATESET: u32 = 73;
+pub(crate) const IOCTL_CMD_GET_GMODE: u32 = 109;
+pub(crate) const IOCTL_CMD_SET_GMODE: u32 = 110;
+pub(crate) const IOCTL_CMD_GET_BAND: u32 = 141;
+pub(crate) const IOCTL_CMD_SET_BAND: u32 = 142;
 pub(crate) const IOCTL_CMD_SET_VAR: u32 = 263;
 pub(crate) const IOCTL_CMD_GET_VAR: u32 = 262;
 pub(crate) const IOCTL_CMD_SET_PASSPHRASE: u32 = 268;
@@ -102,6 +127,67 @@ pub(crate) const IOCTL_CMD_SET_PASSPHRASE: u32 = 268;
 pub(crate) const CHANNEL_TYPE_CONTROL: u8 = 0;
 pub(crate) const CHANNEL_TYPE_EVENT: u8 = 1;
 pub(crate) const CHANNEL_TYPE_DATA: u8 = 2;
+pub(crate) const CHANNEL_TYPE_GLOM: u8 = 3;
+
+// SDPCM channel_and_flags bits
+pub(crate) const SDPCM_CHANNEL_MASK: u8 = 0x0f;
+pub(crate) const SDPCM_GLOMDESC_FLAG: u8 = 0x80;
+
+// Maximum number of subframes in a superframe.
+pub(crate) const SDPCM_MAX_GLOM: usize = 16;
+
+// SDIO CCCR and FBR (function 0) registers.
+pub(crate) const SDIO_CCCR_IOEN: u32 = 0x02;
+pub(crate) const SDIO_CCCR_IORDY: u32 = 0x03;
+pub(crate) const SDIO_CCCR_INTEN: u32 = 0x04;
+pub(crate) const SDIO_FBR_F1_BLOCK_SIZE: u32 = 0x110;
+pub(crate) const SDIO_FBR_F2_BLOCK_SIZE: u32 = 0x210;
+
+// SDIO_CCCR_IOEN, SDIO_CCCR_IORDY and SDIO_CCCR_INTEN bits
+pub(crate) const SDIO_INT_MASTER: u8 = 0x01;
+pub(crate) const SDIO_FUNC_1: u8 = 0x02;
+pub(crate) const SDIO_FUNC_2: u8 = 0x04;
+
+/// Block size of SDIO functions 1 and 2.
+pub(crate) const SDIO_BLOCK_SIZE: usize = 64;
+
+// SDIOD core registers, relative to the core base address.
+pub(crate) const SDIOD_INT_STATUS: u32 = 0x20;
+pub(crate) const SDIOD_HOST_INT_MASK: u32 = 0x24;
+
+// SDIOD_INT_STATUS bits
+pub(crate) const SDIOD_I_HMB_FC_CHANGE: u32 = 1 << 5;
+pub(crate) const SDIOD_I_HMB_FRAME_IND: u32 = 1 << 6;
+pub(crate) const SDIOD_I_WR_OOSYNC: u32 = 1 << 8;
+pub(crate) const SDIOD_I_RD_OOSYNC: u32 = 1 << 9;
+pub(crate) const SDIOD_I_ERRORS: u32 = 0x0000_fc00; // Descriptor, data and DMA FIFO errors
+
+// Bluetooth shared bus registers and buffers, see `bluetooth.rs`.
+pub(crate) const BT2WLAN_PWRUP_ADDR: u32 = 0x0064_0894; // Relative to the BT core's memory
+pub(crate) const BT2WLAN_PWRUP_WAKE: u32 = 3;
+pub(crate) const BT_CTRL_REG_ADDR: u32 = 0x1800_0c7c;
+pub(crate) const HOST_CTRL_REG_ADDR: u32 = 0x1800_0d6c;
+pub(crate) const WLAN_RAM_BASE_REG_ADDR: u32 = 0x1800_0d68;
+
+// HOST_CTRL_REG_ADDR and BT_CTRL_REG_ADDR bits
+pub(crate) const BTSDIO_REG_DATA_VALID: u32 = 1 << 1;
+pub(crate) const BTSDIO_REG_BT_AWAKE: u32 = 1 << 8;
+pub(crate) const BTSDIO_REG_WAKE_BT: u32 = 1 << 17;
+pub(crate) const BTSDIO_REG_SW_RDY: u32 = 1 << 24;
+pub(crate) const BTSDIO_REG_FW_RDY: u32 = 1 << 24;
+
+// HCI ring buffers, relative to the WLAN RAM base.
+pub(crate) const BTSDIO_FWBUF_SIZE: u32 = 0x1000;
+pub(crate) const BTSDIO_OFFSET_HOST_WRITE_BUF: u32 = 0;
+pub(crate) const BTSDIO_OFFSET_HOST_READ_BUF: u32 = BTSDIO_FWBUF_SIZE;
+pub(crate) const BTSDIO_OFFSET_HOST2BT_IN: u32 = 0x2000;
+pub(crate) const BTSDIO_OFFSET_HOST2BT_OUT: u32 = 0x2004;
+pub(crate) const BTSDIO_OFFSET_BT2HOST_IN: u32 = 0x2008;
+pub(crate) const BTSDIO_OFFSET_BT2HOST_OUT: u32 = 0x200c;
+
+// BT firmware patch record types
+pub(crate) const BTFW_RECORD_DATA: u8 = 0;
+pub(crate) const BTFW_RECORD_EXTENDED_ADDRESS: u8 = 4;
 
 // CYW_SPID command structure constants.
 pub(crate) const WRITE: bool = true;
@@ -1,136 +1,287 @@
+use core::cell::Cell;
 use core::cmp::{max, min};
 
 use ch::driver::LinkState;
 use embassy_net_driver_channel as ch;
-use embassy_time::{Duration, Timer};
-
-pub use crate::bus::SpiBusCyw43;
+use embassy_time::{with_timeout, Duration, Timer};
+
+use crate::aggregation::AggregationConfig;
+use crate::antenna::Antenna;
+use crate::bus::BusErrors;
+use crate::coex::{CoexConfig, CoexMode};
+#[cfg(feature = "compressed-firmware")]
+use crate::compress::{self, Decompressor};
+use crate::config::{Config, ControlInitError, InitStep};
 use crate::consts::*;
+use crate::crash::{CrashState, FirmwareCrash};
+use crate::crc::Crc32;
 use crate::events::{Event, EventQueue};
-use crate::fmt::Bytes;
-use crate::ioctl::{IoctlState, IoctlType};
+use crate::firmware::FirmwareSource;
+#[cfg(feature = "firmware-logs")]
+use crate::firmware_log::FirmwareLog;
+use crate::fmt::{utf8_prefix, Bytes};
+use crate::ioctl::{IoctlError, IoctlState, IoctlType};
+use crate::phy::{Band, FixedRate, GMode, NMode, PhyConfig, RateSet, RATE_SET_MAX_LEN};
 use crate::structs::*;
-use crate::{countries, PowerManagementMode};
+use crate::txpower::{TxPower, MAX_TX_POWER_DBM};
+use crate::{ClmError, PowerManagementMode};
+
+/// Maximum length of a firmware console command.
+pub const CONSOLE_COMMAND_MAX_LEN: usize = 58;
+
+/// Error sending a firmware console command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConsoleError {
+    /// The command is longer than [`CONSOLE_COMMAND_MAX_LEN`].
+    TooLong,
+    /// The firmware rejected the command.
+    Ioctl(IoctlError),
+}
+
+/// How many firmware log intervals [`Control::console_command`] waits for more output after the last line.
+#[cfg(feature = "firmware-logs")]
+pub const CONSOLE_QUIET_INTERVALS: u32 = 5;
+
+/// How long to wait for the firmware to respond to an ioctl.
+const IOCTL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Largest iovar value `try_get_iovar_with_params` can read, it must fit [`WmeCounters`].
+const GET_IOVAR_MAX_LEN: usize = 512;
+
+/// Size of the CLM download chunks.
+const CLM_CHUNK_SIZE: usize = 1024;
+
+/// Number of times [`Control::init`] retries the CLM download after a failure.
+pub const CLM_RETRIES: usize = 2;
+
+#[cfg(feature = "compressed-firmware")]
+const _: () = ::core::assert!(compress::CHUNK_SIZE <= CLM_CHUNK_SIZE);
+
+const _: () = ::core::assert!(WmeCounters::SIZE <= GET_IOVAR_MAX_LEN);
 
 pub struct Control<'a> {
     state_ch: ch::StateRunner<'a>,
     event_sub: &'a EventQueue,
     ioctl_state: &'a IoctlState,
+    crash: &'a CrashState,
+    bus_errors: &'a Cell<BusErrors>,
 }
 
 impl<'a> Control<'a> {
-    pub(crate) fn new(state_ch: ch::StateRunner<'a>, event_sub: &'a EventQueue, ioctl_state: &'a IoctlState) -> Self {
+    pub(crate) fn new(
+        state_ch: ch::StateRunner<'a>,
+        event_sub: &'a EventQueue,
+        ioctl_state: &'a IoctlState,
+        crash: &'a CrashState,
+        bus_errors: &'a Cell<BusErrors>,
+    ) -> Self {
         Self {
             state_ch,
             event_sub,
             ioctl_state,
+            crash,
+            bus_errors,
         }
     }
 
-    pub async fn init(&mut self, clm: &[u8]) {
-        const CHUNK_SIZE: usize = 1024;
-
-        info!("Downloading CLM...");
-
-        let mut offs = 0;
-        for chunk in clm.chunks(CHUNK_SIZE) {
-            let mut flag = DOWNLOAD_FLAG_HANDLER_VER;
-            if offs == 0 {
-                flag |= DOWNLOAD_FLAG_BEGIN;
-            }
-            offs += chunk.len();
-            if offs == clm.len() {
-                flag |= DOWNLOAD_FLAG_END;
+    /// Download the CLM from `clm`, usually a byte slice, and configure the chip with `config`. With the
+    /// `compressed-firmware` feature, `clm` may also be compressed with `cyw43-compress`.
+    ///
+    /// The download is retried [`CLM_RETRIES`] times if the firmware rejects it, but not if reading or
+    /// decompressing `clm` fails, as that would fail again. If the firmware
+    /// rejects a setting, the error tells which [`InitStep`] failed.
+    pub async fn init<CLM: FirmwareSource>(&mut self, mut clm: CLM, config: Config) -> Result<(), ControlInitError> {
+        let mut retries = CLM_RETRIES;
+        loop {
+            info!("Downloading CLM...");
+            match self.load_clm(&mut clm).await {
+                Ok(()) => break,
+                Err(e) if retries > 0 && !matches!(e, ClmError::Read | ClmError::Corrupt) => {
+                    warn!("CLM download failed: {:?}, retrying", e);
+                    retries -= 1;
+                }
+                Err(e) => return Err(e.into()),
             }
-
-            let header = DownloadHeader {
-                flag,
-                dload_type: DOWNLOAD_TYPE_CLM,
-                len: chunk.len() as _,
-                crc: 0,
-            };
-            let mut buf = [0; 8 + 12 + CHUNK_SIZE];
-            buf[0..8].copy_from_slice(b"clmload\x00");
-            buf[8..20].copy_from_slice(&header.to_bytes());
-            buf[20..][..chunk.len()].copy_from_slice(&chunk);
-            self.ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, &mut buf[..8 + 12 + chunk.len()])
-                .await;
         }
 
-        // check clmload ok
-        assert_eq!(self.get_iovar_u32("clmload_status").await, 0);
-
         info!("Configuring misc stuff...");
 
-        // Disable tx gloming which transfers multiple packets in one request.
         // 'glom' is short for "conglomerate" which means "gather together into
-        // a compact mass".
-        self.set_iovar_u32("bus:txglom", 0).await;
-        self.set_iovar_u32("apsta", 1).await;
+        // a compact mass": several packets are transferred in one request.
+        self.try_set_iovar_u32("bus:txglom", config.tx_glom as u32)
+            .await
+            .map_err(InitStep::TxGlom.failed())?;
+        self.try_set_iovar_u32("apsta", config.apsta as u32)
+            .await
+            .map_err(InitStep::ApSta.failed())?;
 
         // read MAC addr.
         let mut mac_addr = [0; 6];
-        assert_eq!(self.get_iovar("cur_etheraddr", &mut mac_addr).await, 6);
+        let len = self
+            .try_get_iovar("cur_etheraddr", &mut mac_addr)
+            .await
+            .map_err(InitStep::MacAddress.failed())?;
+        if len != 6 {
+            return Err(ControlInitError::Step(
+                InitStep::MacAddress,
+                IoctlError::InvalidResponse,
+            ));
+        }
         info!("mac addr: {:02x}", Bytes(&mac_addr));
 
-        let country = countries::WORLD_WIDE_XX;
+        let country = config.country;
         let country_info = CountryInfo {
             country_abbrev: [country.code[0], country.code[1], 0, 0],
             country_code: [country.code[0], country.code[1], 0, 0],
             rev: if country.rev == 0 { -1 } else { country.rev as _ },
         };
-        self.set_iovar("country", &country_info.to_bytes()).await;
+        self.try_set_iovar("country", &country_info.to_bytes())
+            .await
+            .map_err(InitStep::Country.failed())?;
 
         // set country takes some time, next ioctls fail if we don't wait.
-        Timer::after(Duration::from_millis(100)).await;
-
-        // Set antenna to chip antenna
-        self.ioctl_set_u32(IOCTL_CMD_ANTDIV, 0, 0).await;
+        Timer::after(config.settle_time).await;
 
-        self.set_iovar_u32("bus:txglom", 0).await;
-        Timer::after(Duration::from_millis(100)).await;
-        //self.set_iovar_u32("apsta", 1).await; // this crashes, also we already did it before...??
-        //Timer::after(Duration::from_millis(100)).await;
-        self.set_iovar_u32("ampdu_ba_wsize", 8).await;
-        Timer::after(Duration::from_millis(100)).await;
-        self.set_iovar_u32("ampdu_mpdu", 4).await;
-        Timer::after(Duration::from_millis(100)).await;
-        //self.set_iovar_u32("ampdu_rx_factor", 0).await; // this crashes
+        self.try_ioctl_set_u32(IOCTL_CMD_SET_ANTDIV, 0, config.antenna.antdiv())
+            .await
+            .map_err(InitStep::Antenna.failed())?;
+        // The firmware transmits on the antenna it last received on, which is the chip antenna
+        // unless another one is selected.
+        if config.antenna != Antenna::Chip {
+            self.try_ioctl_set_u32(IOCTL_CMD_SET_TXANT, 0, config.antenna.txant())
+                .await
+                .map_err(InitStep::Antenna.failed())?;
+        }
 
-        //Timer::after(Duration::from_millis(100)).await;
+        self.set_aggregation(config.aggregation)
+            .await
+            .map_err(InitStep::Aggregation.failed())?;
+        // ampdu_rx_factor is left alone, setting it crashes the firmware.
+        Timer::after(config.settle_time).await;
 
-        // evts
         let mut evts = EventMask {
             iface: 0,
             events: [0xFF; 24],
         };
+        for &event in config.disabled_events {
+            evts.unset(event);
+        }
+        self.try_set_iovar("bsscfg:event_msgs", &evts.to_bytes())
+            .await
+            .map_err(InitStep::EventMask.failed())?;
 
-        // Disable spammy uninteresting events.
-        evts.unset(Event::RADIO);
-        evts.unset(Event::IF);
-        evts.unset(Event::PROBREQ_MSG);
-        evts.unset(Event::PROBREQ_MSG_RX);
-        evts.unset(Event::PROBRESP_MSG);
-        evts.unset(Event::PROBRESP_MSG);
-        evts.unset(Event::ROAM);
-
-        self.set_iovar("bsscfg:event_msgs", &evts.to_bytes()).await;
+        Timer::after(config.settle_time).await;
 
-        Timer::after(Duration::from_millis(100)).await;
+        // The 802.11n mode can only be changed while the interface is down. The firmware starts
+        // with it enabled.
+        if config.phy.nmode != NMode::Enabled {
+            self.apply_nmode(config.phy.nmode)
+                .await
+                .map_err(InitStep::Phy.failed())?;
+        }
 
         // set wifi up
-        self.ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await;
+        self.try_ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut [])
+            .await
+            .map_err(InitStep::Up.failed())?;
 
-        Timer::after(Duration::from_millis(100)).await;
+        Timer::after(config.settle_time).await;
 
-        self.ioctl_set_u32(110, 0, 1).await; // SET_GMODE = auto
-        self.ioctl_set_u32(142, 0, 0).await; // SET_BAND = any
+        self.apply_rates(config.phy).await.map_err(InitStep::Phy.failed())?;
 
-        Timer::after(Duration::from_millis(100)).await;
+        Timer::after(config.settle_time).await;
 
         self.state_ch.set_ethernet_address(mac_addr);
 
         info!("INIT DONE");
+        Ok(())
+    }
+
+    /// Get the version information of the loaded CLM, the regulatory data, into `buf`. This is a
+    /// few lines like `API: 12.2`, `Data: 9.10.39`, `Creation: 2021-07-19 20:12:36`.
+    pub async fn clm_version<'b>(&mut self, buf: &'b mut [u8]) -> Result<&'b str, IoctlError> {
+        // get_iovar can't return more than its own buffer.
+        let buf_len = buf.len().min(GET_IOVAR_MAX_LEN);
+        let len = self.try_get_iovar("clmver", &mut buf[..buf_len]).await?;
+        let s = &buf[..len.min(buf_len)];
+        let s = &s[..s.iter().position(|&b| b == 0).unwrap_or(s.len())];
+        Ok(utf8_prefix(s).trim_end())
+    }
+
+    /// Download the CLM, decompressing it first if it is compressed, and check the firmware accepted it.
+    async fn load_clm<CLM: FirmwareSource>(&mut self, clm: &mut CLM) -> Result<(), ClmError> {
+        let mut buf = [0; 8 + 12 + CLM_CHUNK_SIZE];
+
+        #[cfg(feature = "compressed-firmware")]
+        if compress::is_compressed(clm).await? {
+            let mut clm = Decompressor::new(clm).await?;
+            let len = clm.len();
+            let mut offs = 0;
+            while let Some(chunk) = clm.next_chunk().await? {
+                buf[20..][..chunk.len()].copy_from_slice(chunk);
+                self.load_clm_chunk(&mut buf, chunk.len(), offs, len).await?;
+                offs += chunk.len();
+            }
+            return self.clm_status().await;
+        }
+
+        let len = clm.len();
+        let mut offs = 0;
+        while offs < len {
+            let n = (len - offs).min(CLM_CHUNK_SIZE);
+            clm.read(offs, &mut buf[20..][..n]).await?;
+            self.load_clm_chunk(&mut buf, n, offs, len).await?;
+            offs += n;
+        }
+        self.clm_status().await
+    }
+
+    /// Check whether the firmware accepted the downloaded CLM.
+    async fn clm_status(&mut self) -> Result<(), ClmError> {
+        let status = self
+            .try_get_iovar_u32("clmload_status")
+            .await
+            .map_err(|_| ClmError::IovarError)?;
+        match ClmError::from_status(status) {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// Download the `chunk_len` bytes at `offs` of a `len` bytes CLM. The chunk is already in
+    /// `buf`, after the space for the iovar name and download header. The header carries the
+    /// CRC-32 of the chunk for the firmware to check it against.
+    async fn load_clm_chunk(
+        &mut self,
+        buf: &mut [u8; 8 + 12 + CLM_CHUNK_SIZE],
+        chunk_len: usize,
+        offs: usize,
+        len: usize,
+    ) -> Result<(), ClmError> {
+        let mut crc = Crc32::new();
+        crc.update(&buf[20..][..chunk_len]);
+
+        let mut flag = DOWNLOAD_FLAG_HANDLER_VER;
+        if offs == 0 {
+            flag |= DOWNLOAD_FLAG_BEGIN;
+        }
+        if offs + chunk_len == len {
+            flag |= DOWNLOAD_FLAG_END;
+        }
+
+        let header = DownloadHeader {
+            flag,
+            dload_type: DOWNLOAD_TYPE_CLM,
+            len: chunk_len as _,
+            crc: crc.finish(),
+        };
+        buf[0..8].copy_from_slice(b"clmload\x00");
+        buf[8..20].copy_from_slice(&header.to_bytes());
+        self.try_ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, &mut buf[..8 + 12 + chunk_len])
+            .await
+            .map_err(|_| ClmError::IovarError)?;
+        Ok(())
     }
 
     pub async fn set_power_management(&mut self, mode: PowerManagementMode) {
@@ -145,8 +296,9 @@ impl<'a> Control<'a> {
         self.ioctl_set_u32(86, 0, mode_num).await;
     }
 
-    pub async fn join_open(&mut self, ssid: &str) {
-        self.set_iovar_u32("ampdu_ba_wsize", 8).await;
+    /// Join the open network `ssid`, with A-MPDU `aggregation` for the connection.
+    pub async fn join_open(&mut self, ssid: &str, aggregation: AggregationConfig) {
+        unwrap!(self.set_aggregation(aggregation).await);
 
         self.ioctl_set_u32(134, 0, 0).await; // wsec = open
         self.set_iovar_u32x2("bsscfg:sup_wpa", 0, 0).await;
@@ -162,8 +314,9 @@ impl<'a> Control<'a> {
         self.wait_for_join(i).await;
     }
 
-    pub async fn join_wpa2(&mut self, ssid: &str, passphrase: &str) {
-        self.set_iovar_u32("ampdu_ba_wsize", 8).await;
+    /// Join the WPA2 network `ssid`, with A-MPDU `aggregation` for the connection.
+    pub async fn join_wpa2(&mut self, ssid: &str, passphrase: &str, aggregation: AggregationConfig) {
+        unwrap!(self.set_aggregation(aggregation).await);
 
         self.ioctl_set_u32(134, 0, 4).await; // wsec = wpa2
         self.set_iovar_u32x2("bsscfg:sup_wpa", 0, 1).await;
@@ -217,6 +370,277 @@ impl<'a> Control<'a> {
         info!("JOINED");
     }
 
+    /// Returns the crash report if the WLAN firmware has crashed.
+    ///
+    /// The runner checks the firmware's shared memory for traps and failed asserts once per second.
+    pub fn firmware_crash(&self) -> Option<FirmwareCrash> {
+        self.crash.get()
+    }
+
+    /// Wait until the WLAN firmware crashes, and return the crash report.
+    pub async fn wait_firmware_crash(&self) -> FirmwareCrash {
+        self.crash.wait().await
+    }
+
+    /// Counters of the gSPI bus errors the runner has recovered from so far.
+    pub fn bus_errors(&self) -> BusErrors {
+        self.bus_errors.get()
+    }
+
+    /// Send a command to the WLAN firmware's debug console, e.g. `"mu"` or `"wlc:dump"`.
+    ///
+    /// Output of the command appears on the firmware console, see [`console_command`](Self::console_command)
+    /// to collect it. `cmd` can be at most [`CONSOLE_COMMAND_MAX_LEN`] bytes long.
+    pub async fn console_write(&mut self, cmd: &str) -> Result<(), ConsoleError> {
+        if cmd.len() > CONSOLE_COMMAND_MAX_LEN {
+            return Err(ConsoleError::TooLong);
+        }
+
+        // The command is passed as a NUL terminated string.
+        let mut buf = [0; CONSOLE_COMMAND_MAX_LEN + 1];
+        buf[..cmd.len()].copy_from_slice(cmd.as_bytes());
+        self.try_set_iovar("cons", &buf[..cmd.len() + 1])
+            .await
+            .map_err(ConsoleError::Ioctl)
+    }
+
+    /// Send a command to the WLAN firmware's debug console and collect its output from `log`.
+    ///
+    /// Lines already buffered in `log` are discarded first. The output lines are then copied into
+    /// `out`, separated by `\n`, until no new line arrived for [`CONSOLE_QUIET_INTERVALS`] of the
+    /// runner's [firmware log interval](FirmwareLog::interval). Output that doesn't fit in `out` is
+    /// discarded. Returns the number of bytes written to `out`.
+    #[cfg(feature = "firmware-logs")]
+    pub async fn console_command(
+        &mut self,
+        cmd: &str,
+        log: &mut FirmwareLog<'_>,
+        out: &mut [u8],
+    ) -> Result<usize, ConsoleError> {
+        while log.try_read_line(&mut []).is_some() {}
+
+        self.console_write(cmd).await?;
+
+        let quiet_time = log.interval() * CONSOLE_QUIET_INTERVALS;
+        let mut n = 0;
+        while let Ok(len) = with_timeout(quiet_time, log.read_line(&mut out[n..])).await {
+            n += len;
+            if n < out.len() {
+                out[n] = b'\n';
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Read the firmware's per access category traffic counters.
+    pub async fn wme_counters(&mut self) -> Result<WmeCounters, IoctlError> {
+        let mut buf = [0; WmeCounters::SIZE];
+        let len = self.try_get_iovar("wme_counters", &mut buf).await?;
+        let counters = WmeCounters::from_bytes(&buf);
+        if len != WmeCounters::SIZE || counters.version != WME_COUNTERS_VERSION {
+            warn!("unexpected wme_counters, len={} version={}", len, counters.version);
+            return Err(IoctlError::InvalidResponse);
+        }
+        Ok(counters)
+    }
+
+    /// Configure Wi-Fi/Bluetooth coexistence.
+    pub async fn set_coex(&mut self, config: CoexConfig<'_>) -> Result<(), IoctlError> {
+        self.try_set_iovar_u32("btc_mode", config.mode as u32).await?;
+        for &(index, value) in config.params {
+            let mut buf = [0; 8];
+            buf[0..4].copy_from_slice(&index.to_le_bytes());
+            buf[4..8].copy_from_slice(&value.to_le_bytes());
+            self.try_set_iovar("btc_params", &buf).await?;
+        }
+        Ok(())
+    }
+
+    /// Current coexistence mode. `None` if the firmware reports a mode unknown to this driver.
+    pub async fn coex_mode(&mut self) -> Result<Option<CoexMode>, IoctlError> {
+        let mode = self.try_get_iovar_u32("btc_mode").await?;
+        Ok(CoexMode::from_u32(mode))
+    }
+
+    /// Current value of the coexistence parameter `index`, see [`CoexConfig::params`].
+    pub async fn coex_param(&mut self, index: u32) -> Result<u32, IoctlError> {
+        let mut buf = [0; 4];
+        let len = self
+            .try_get_iovar_with_params("btc_params", &index.to_le_bytes(), &mut buf)
+            .await?;
+        if len != 4 {
+            return Err(IoctlError::InvalidResponse);
+        }
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    /// Select the antenna to receive and transmit on.
+    pub async fn set_antenna(&mut self, antenna: Antenna) -> Result<(), IoctlError> {
+        self.try_ioctl_set_u32(IOCTL_CMD_SET_ANTDIV, 0, antenna.antdiv())
+            .await?;
+        self.try_ioctl_set_u32(IOCTL_CMD_SET_TXANT, 0, antenna.txant()).await
+    }
+
+    /// Current antenna. `None` if the receive and transmit antennas don't match an [`Antenna`].
+    pub async fn antenna(&mut self) -> Result<Option<Antenna>, IoctlError> {
+        let antdiv = self.try_ioctl_get_u32(IOCTL_CMD_GET_ANTDIV, 0).await?;
+        let txant = self.try_ioctl_get_u32(IOCTL_CMD_GET_TXANT, 0).await?;
+        Ok(Antenna::from_antdiv(antdiv).filter(|antenna| txant == antenna.txant()))
+    }
+
+    /// Limit the transmit power to `dbm`, at most [`MAX_TX_POWER_DBM`]. The firmware still
+    /// transmits at most the maximum allowed in the current country and by the board. Returns the
+    /// resulting transmit power, see [`tx_power`](Self::tx_power).
+    pub async fn set_tx_power(&mut self, dbm: u8) -> Result<TxPower, IoctlError> {
+        let dbm = min(dbm, MAX_TX_POWER_DBM);
+        self.try_set_iovar_u32("qtxpower", dbm as u32 * 4).await?;
+
+        let power = self.tx_power().await?;
+        if let (true, Some(target)) = (power.is_clamped(), power.target_qdbm) {
+            info!("tx power clamped to {} qdBm", target);
+        }
+        Ok(power)
+    }
+
+    /// Current transmit power limit, and the power the firmware targets after the country and
+    /// board maximums.
+    pub async fn tx_power(&mut self) -> Result<TxPower, IoctlError> {
+        let limit_qdbm = (self.try_get_iovar_u32("qtxpower").await? & !TXPWR_OVERRIDE) as u8;
+
+        let mut buf = [0; TxPowerTargetMax::SIZE];
+        // Not all firmware builds have txpwr_target_max.
+        let len = self.try_get_iovar("txpwr_target_max", &mut buf).await.unwrap_or(0);
+        let max = TxPowerTargetMax::from_bytes(&buf);
+        let cores = &max.txpwr[..min(max.rf_cores as usize, max.txpwr.len())];
+        let target_qdbm = if len >= TXPWR_TARGET_MAX_LEN && max.version == TXPWR_TARGET_VERSION {
+            cores.iter().copied().max()
+        } else {
+            None
+        };
+
+        Ok(TxPower {
+            limit_qdbm,
+            target_qdbm,
+        })
+    }
+
+    /// Configure the 802.11 modes, band and rates. Takes effect on the next join or AP start.
+    ///
+    /// The interface is brought down to change them and up again afterwards, which drops the
+    /// current connection or stops the AP.
+    pub async fn set_phy(&mut self, config: PhyConfig) -> Result<(), IoctlError> {
+        self.try_ioctl(IoctlType::Set, IOCTL_CMD_DOWN, 0, &mut []).await?;
+        let res = self.apply_phy(config).await;
+        // Bring the interface up again even if a setting was rejected.
+        self.try_ioctl(IoctlType::Set, IOCTL_CMD_UP, 0, &mut []).await?;
+        res
+    }
+
+    /// Write `config` to the firmware. The interface must be down.
+    async fn apply_phy(&mut self, config: PhyConfig) -> Result<(), IoctlError> {
+        self.apply_nmode(config.nmode).await?;
+        self.apply_rates(config).await?;
+        if config.fixed_rate.is_none() {
+            // Firmware builds without 2g_rate always pick the rate, nothing to clear there.
+            self.try_set_iovar_u32("2g_rate", 0).await.ok();
+        }
+        Ok(())
+    }
+
+    /// Write the 802.11g mode, band and the rates `config` sets.
+    async fn apply_rates(&mut self, config: PhyConfig) -> Result<(), IoctlError> {
+        self.try_ioctl_set_u32(IOCTL_CMD_SET_GMODE, 0, config.gmode as u32)
+            .await?;
+        self.try_ioctl_set_u32(IOCTL_CMD_SET_BAND, 0, config.band as u32)
+            .await?;
+
+        if let Some(rates) = config.rates {
+            let mut buf = rates.to_bytes();
+            self.try_ioctl(IoctlType::Set, IOCTL_CMD_SET_RATESET, 0, &mut buf)
+                .await?;
+        }
+        if let Some(rate) = config.fixed_rate {
+            self.try_set_iovar_u32("2g_rate", rate.to_ratespec()).await?;
+        }
+        Ok(())
+    }
+
+    /// Write the 802.11n mode. The interface must be down.
+    async fn apply_nmode(&mut self, nmode: NMode) -> Result<(), IoctlError> {
+        self.try_set_iovar_u32("nmode", (nmode != NMode::Disabled) as u32)
+            .await?;
+        match nmode {
+            NMode::Required => self.try_set_iovar_u32("nreqd", 1).await,
+            // Firmware builds without nreqd can't require 802.11n, nothing to clear there.
+            _ => {
+                self.try_set_iovar_u32("nreqd", 0).await.ok();
+                Ok(())
+            }
+        }
+    }
+
+    /// Current 802.11 configuration. `None` if the firmware reports a gmode or band unknown to
+    /// this driver. [`PhyConfig::rates`] is always set, to the rate set in use.
+    pub async fn phy(&mut self) -> Result<Option<PhyConfig>, IoctlError> {
+        let gmode = GMode::from_u32(self.try_ioctl_get_u32(IOCTL_CMD_GET_GMODE, 0).await?);
+        let band = Band::from_u32(self.try_ioctl_get_u32(IOCTL_CMD_GET_BAND, 0).await?);
+        let (Some(gmode), Some(band)) = (gmode, band) else {
+            return Ok(None);
+        };
+
+        let nmode = self.nmode().await?;
+
+        let mut buf = [0; 4 + RATE_SET_MAX_LEN];
+        let len = self
+            .try_ioctl(IoctlType::Get, IOCTL_CMD_GET_RATESET, 0, &mut buf)
+            .await?;
+        if len < 4 {
+            return Err(IoctlError::InvalidResponse);
+        }
+        let rates = RateSet::from_bytes(&buf);
+
+        let fixed_rate = self.fixed_rate().await;
+
+        Ok(Some(PhyConfig {
+            gmode,
+            band,
+            nmode,
+            rates: Some(rates),
+            fixed_rate,
+        }))
+    }
+
+    async fn nmode(&mut self) -> Result<NMode, IoctlError> {
+        // Firmware builds without nreqd can't require 802.11n.
+        let nreqd = self.try_get_iovar_u32("nreqd").await.unwrap_or(0);
+        Ok(match (self.try_get_iovar_u32("nmode").await?, nreqd) {
+            (0, _) => NMode::Disabled,
+            (_, 0) => NMode::Enabled,
+            _ => NMode::Required,
+        })
+    }
+
+    async fn fixed_rate(&mut self) -> Option<FixedRate> {
+        // Firmware builds without 2g_rate always pick the rate.
+        let ratespec = self.try_get_iovar_u32("2g_rate").await.unwrap_or(0);
+        FixedRate::from_ratespec(ratespec)
+    }
+
+    /// Configure A-MPDU aggregation. Joins set their own, see [`join_open`](Self::join_open).
+    pub async fn set_aggregation(&mut self, config: AggregationConfig) -> Result<(), IoctlError> {
+        self.try_set_iovar_u32("ampdu_ba_wsize", config.ba_wsize() as u32)
+            .await?;
+        self.try_set_iovar_u32("ampdu_mpdu", config.mpdu() as u32).await
+    }
+
+    /// Current A-MPDU aggregation settings of the firmware.
+    pub async fn aggregation(&mut self) -> Result<AggregationConfig, IoctlError> {
+        let ba_wsize = self.try_get_iovar_u32("ampdu_ba_wsize").await?;
+        let mpdu = self.try_get_iovar_u32("ampdu_mpdu").await?;
+        AggregationConfig::from_firmware(ba_wsize, mpdu).ok_or(IoctlError::InvalidResponse)
+    }
+
     pub async fn gpio_set(&mut self, gpio_n: u8, gpio_en: bool) {
         assert!(gpio_n < 3);
         self.set_iovar_u32x2("gpioout", 1 << gpio_n, if gpio_en { 1 << gpio_n } else { 0 })
@@ -231,17 +655,27 @@ impl<'a> Control<'a> {
     }
 
     async fn set_iovar_u32(&mut self, name: &str, val: u32) {
-        self.set_iovar(name, &val.to_le_bytes()).await
+        unwrap!(self.try_set_iovar_u32(name, val).await)
+    }
+
+    async fn try_set_iovar_u32(&mut self, name: &str, val: u32) -> Result<(), IoctlError> {
+        self.try_set_iovar(name, &val.to_le_bytes()).await
     }
 
-    async fn get_iovar_u32(&mut self, name: &str) -> u32 {
+    async fn try_get_iovar_u32(&mut self, name: &str) -> Result<u32, IoctlError> {
         let mut buf = [0; 4];
-        let len = self.get_iovar(name, &mut buf).await;
-        assert_eq!(len, 4);
-        u32::from_le_bytes(buf)
+        let len = self.try_get_iovar(name, &mut buf).await?;
+        if len != 4 {
+            return Err(IoctlError::InvalidResponse);
+        }
+        Ok(u32::from_le_bytes(buf))
     }
 
     async fn set_iovar(&mut self, name: &str, val: &[u8]) {
+        unwrap!(self.try_set_iovar(name, val).await)
+    }
+
+    async fn try_set_iovar(&mut self, name: &str, val: &[u8]) -> Result<(), IoctlError> {
         info!("set {} = {:02x}", name, Bytes(val));
 
         let mut buf = [0; 64];
@@ -250,34 +684,63 @@ impl<'a> Control<'a> {
         buf[name.len() + 1..][..val.len()].copy_from_slice(val);
 
         let total_len = name.len() + 1 + val.len();
-        self.ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, &mut buf[..total_len])
-            .await;
+        self.try_ioctl(IoctlType::Set, IOCTL_CMD_SET_VAR, 0, &mut buf[..total_len])
+            .await?;
+        Ok(())
     }
 
-    // TODO this is not really working, it always returns all zeros.
-    async fn get_iovar(&mut self, name: &str, res: &mut [u8]) -> usize {
-        info!("get {}", name);
+    async fn try_get_iovar(&mut self, name: &str, res: &mut [u8]) -> Result<usize, IoctlError> {
+        self.try_get_iovar_with_params(name, &[], res).await
+    }
 
-        let mut buf = [0; 64];
+    /// Get an iovar that takes parameters, e.g. the index of a table entry. They follow the name.
+    async fn try_get_iovar_with_params(
+        &mut self,
+        name: &str,
+        params: &[u8],
+        res: &mut [u8],
+    ) -> Result<usize, IoctlError> {
+        info!("get {} {:02x}", name, Bytes(params));
+
+        let mut buf = [0; GET_IOVAR_MAX_LEN];
         buf[..name.len()].copy_from_slice(name.as_bytes());
         buf[name.len()] = 0;
+        buf[name.len() + 1..][..params.len()].copy_from_slice(params);
 
-        let total_len = max(name.len() + 1, res.len());
+        let total_len = max(name.len() + 1 + params.len(), res.len());
         let res_len = self
-            .ioctl(IoctlType::Get, IOCTL_CMD_GET_VAR, 0, &mut buf[..total_len])
-            .await;
+            .try_ioctl(IoctlType::Get, IOCTL_CMD_GET_VAR, 0, &mut buf[..total_len])
+            .await?;
 
         let out_len = min(res.len(), res_len);
         res[..out_len].copy_from_slice(&buf[..out_len]);
-        out_len
+        Ok(out_len)
     }
 
     async fn ioctl_set_u32(&mut self, cmd: u32, iface: u32, val: u32) {
+        unwrap!(self.try_ioctl_set_u32(cmd, iface, val).await)
+    }
+
+    async fn try_ioctl_set_u32(&mut self, cmd: u32, iface: u32, val: u32) -> Result<(), IoctlError> {
         let mut buf = val.to_le_bytes();
-        self.ioctl(IoctlType::Set, cmd, iface, &mut buf).await;
+        self.try_ioctl(IoctlType::Set, cmd, iface, &mut buf).await?;
+        Ok(())
+    }
+
+    async fn try_ioctl_get_u32(&mut self, cmd: u32, iface: u32) -> Result<u32, IoctlError> {
+        let mut buf = [0; 4];
+        let len = self.try_ioctl(IoctlType::Get, cmd, iface, &mut buf).await?;
+        if len != 4 {
+            return Err(IoctlError::InvalidResponse);
+        }
+        Ok(u32::from_le_bytes(buf))
     }
 
     async fn ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> usize {
+        unwrap!(self.try_ioctl(kind, cmd, iface, buf).await)
+    }
+
+    async fn try_ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> Result<usize, IoctlError> {
         struct CancelOnDrop<'a>(&'a IoctlState);
 
         impl CancelOnDrop<'_> {
@@ -294,11 +757,13 @@ impl<'a> Control<'a> {
 
         let ioctl = CancelOnDrop(self.ioctl_state);
 
-        ioctl.0.do_ioctl(kind, cmd, iface, buf).await;
-        let resp_len = ioctl.0.wait_complete().await;
+        // On timeout the ioctl is cancelled when dropped, a late response no longer matches its id.
+        let result = with_timeout(IOCTL_TIMEOUT, ioctl.0.do_ioctl(kind, cmd, iface, buf))
+            .await
+            .map_err(|_| IoctlError::Timeout)?;
 
         ioctl.defuse();
 
-        resp_len
+        result
     }
 }
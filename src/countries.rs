@@ -1,5 +1,10 @@
+//! Countries for [`Config::country`](crate::Config::country).
+
 #![allow(unused)]
 
+/// A country, by ISO 3166 code and revision of its regulatory rules in the CLM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Country {
     pub code: [u8; 2],
     pub rev: u16,
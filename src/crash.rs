@@ -0,0 +1,116 @@
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+
+use embassy_sync::waitqueue::WakerRegistration;
+
+use crate::fmt::utf8_prefix;
+pub use crate::structs::TrapInfo;
+
+/// Maximum length of the assert expression and file name kept in a [`FirmwareCrash`].
+pub const ASSERT_STR_LEN: usize = 64;
+
+/// Report of a WLAN firmware crash, read from the firmware's shared memory area.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareCrash {
+    /// Raw flags of the shared memory area.
+    pub flags: u32,
+    /// Register dump, if the firmware trapped.
+    pub trap: Option<TrapInfo>,
+    /// Failed assertion, if the firmware hit one.
+    pub assert: Option<AssertInfo>,
+}
+
+/// Failed firmware assertion.
+#[derive(Clone)]
+pub struct AssertInfo {
+    pub line: u32,
+    expr: [u8; ASSERT_STR_LEN],
+    expr_len: usize,
+    file: [u8; ASSERT_STR_LEN],
+    file_len: usize,
+}
+
+impl AssertInfo {
+    pub(crate) fn new(line: u32, expr: &[u8], file: &[u8]) -> Self {
+        let mut this = Self {
+            line,
+            expr: [0; ASSERT_STR_LEN],
+            expr_len: expr.len().min(ASSERT_STR_LEN),
+            file: [0; ASSERT_STR_LEN],
+            file_len: file.len().min(ASSERT_STR_LEN),
+        };
+        this.expr[..this.expr_len].copy_from_slice(&expr[..this.expr_len]);
+        this.file[..this.file_len].copy_from_slice(&file[..this.file_len]);
+        this
+    }
+
+    /// The failed expression, if the firmware reported one. Truncated to [`ASSERT_STR_LEN`] bytes.
+    pub fn expr(&self) -> &str {
+        utf8_prefix(&self.expr[..self.expr_len])
+    }
+
+    /// The source file of the assertion. Truncated to [`ASSERT_STR_LEN`] bytes.
+    pub fn file(&self) -> &str {
+        utf8_prefix(&self.file[..self.file_len])
+    }
+}
+
+impl core::fmt::Debug for AssertInfo {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("AssertInfo")
+            .field("line", &self.line)
+            .field("expr", &self.expr())
+            .field("file", &self.file())
+            .finish()
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for AssertInfo {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "AssertInfo {{ line: {}, expr: {}, file: {} }}",
+            self.line,
+            self.expr(),
+            self.file()
+        )
+    }
+}
+
+/// Holds the crash report between the runner, which detects it, and `Control`.
+pub struct CrashState {
+    crash: RefCell<Option<FirmwareCrash>>,
+    waker: RefCell<WakerRegistration>,
+}
+
+impl CrashState {
+    pub fn new() -> Self {
+        Self {
+            crash: RefCell::new(None),
+            waker: RefCell::new(WakerRegistration::new()),
+        }
+    }
+
+    pub fn crashed(&self, crash: FirmwareCrash) {
+        *self.crash.borrow_mut() = Some(crash);
+        self.waker.borrow_mut().wake();
+    }
+
+    pub fn get(&self) -> Option<FirmwareCrash> {
+        self.crash.borrow().clone()
+    }
+
+    pub async fn wait(&self) -> FirmwareCrash {
+        poll_fn(|cx| match self.get() {
+            Some(crash) => Poll::Ready(crash),
+            None => {
+                self.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
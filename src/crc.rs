@@ -0,0 +1,38 @@
+/// CRC-32 as used by zlib and Ethernet, computed incrementally with a nibble table.
+#[derive(Clone, Copy)]
+pub(crate) struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 16] = {
+    let mut table = [0; 16];
+    let mut i = 0;
+    while i < 16 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 4 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    pub(crate) const fn new() -> Self {
+        Self(!0)
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        let mut crc = self.0;
+        for &b in data {
+            crc = CRC32_TABLE[((crc ^ b as u32) & 0xf) as usize] ^ (crc >> 4);
+            crc = CRC32_TABLE[((crc ^ (b as u32 >> 4)) & 0xf) as usize] ^ (crc >> 4);
+        }
+        self.0 = crc;
+    }
+
+    pub(crate) fn finish(self) -> u32 {
+        !self.0
+    }
+}
@@ -0,0 +1,154 @@
+use crate::InitError;
+
+/// A [`FirmwareSource`] failed to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareReadError;
+
+impl From<FirmwareReadError> for InitError {
+    fn from(_: FirmwareReadError) -> Self {
+        InitError::FirmwareRead
+    }
+}
+
+/// Where the firmware and CLM blobs are read from.
+///
+/// Blobs are read in chunks while they are loaded into the chip, so they don't have to be memory
+/// mapped. Implemented for byte slices and arrays, for flash through [`FlashSource`] (with the
+/// `embedded-storage` feature) and for closures through [`FnSource`].
+pub trait FirmwareSource {
+    /// Length of the blob in bytes.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Fill `buf` with the bytes at `offset`. `offset + buf.len()` is never past [`len`](Self::len).
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError>;
+}
+
+impl FirmwareSource for &[u8] {
+    fn len(&self) -> usize {
+        <[u8]>::len(self)
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError> {
+        buf.copy_from_slice(&self[offset..][..buf.len()]);
+        Ok(())
+    }
+}
+
+impl<const N: usize> FirmwareSource for &[u8; N] {
+    fn len(&self) -> usize {
+        N
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError> {
+        buf.copy_from_slice(&self[offset..][..buf.len()]);
+        Ok(())
+    }
+}
+
+impl<T: FirmwareSource> FirmwareSource for &mut T {
+    fn len(&self) -> usize {
+        T::len(self)
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError> {
+        T::read(self, offset, buf).await
+    }
+}
+
+/// A [`FirmwareSource`] calling a closure to read, e.g. from a file system.
+pub struct FnSource<F> {
+    len: usize,
+    read: F,
+}
+
+impl<F> FnSource<F>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<(), FirmwareReadError>,
+{
+    /// A blob of `len` bytes, read by `read(offset, buf)`.
+    pub fn new(len: usize, read: F) -> Self {
+        Self { len, read }
+    }
+}
+
+impl<F> FirmwareSource for FnSource<F>
+where
+    F: FnMut(usize, &mut [u8]) -> Result<(), FirmwareReadError>,
+{
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    async fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), FirmwareReadError> {
+        (self.read)(offset, buf)
+    }
+}
+
+/// Size of the bounce buffer of [`FlashSource`], for reads that aren't aligned to the flash's `READ_SIZE`.
+#[cfg(feature = "embedded-storage")]
+const SCRATCH_LEN: usize = 64;
+
+/// A [`FirmwareSource`] reading a blob stored in NOR flash, e.g. on an external SPI flash or in an
+/// OTA partition.
+#[cfg(feature = "embedded-storage")]
+pub struct FlashSource<F> {
+    flash: F,
+    offset: u32,
+    len: usize,
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<F: embedded_storage::nor_flash::ReadNorFlash> FlashSource<F> {
+    const READ_SIZE_OK: () = ::core::assert!(
+        F::READ_SIZE <= SCRATCH_LEN && SCRATCH_LEN % F::READ_SIZE == 0,
+        "flash READ_SIZE must divide the FlashSource scratch buffer"
+    );
+
+    /// A blob of `len` bytes at `offset` in `flash`. The flash's `READ_SIZE` must divide 64 bytes,
+    /// which is checked at compile time.
+    pub fn new(flash: F, offset: u32, len: usize) -> Self {
+        let _ = Self::READ_SIZE_OK;
+        Self { flash, offset, len }
+    }
+
+    /// Give back the flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<F: embedded_storage::nor_flash::ReadNorFlash> FirmwareSource for FlashSource<F> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    async fn read(&mut self, offset: usize, mut buf: &mut [u8]) -> Result<(), FirmwareReadError> {
+        let mut addr = self.offset + offset as u32;
+        let align = F::READ_SIZE as u32;
+
+        if addr % align == 0 && buf.len() as u32 % align == 0 {
+            return self.flash.read(addr, buf).map_err(|_| FirmwareReadError);
+        }
+
+        // Read the aligned span around each piece, and copy out the part that was asked for.
+        let mut scratch = [0; SCRATCH_LEN];
+        while !buf.is_empty() {
+            let skip = (addr % align) as usize;
+            let n = buf.len().min(SCRATCH_LEN - skip);
+            let span = (skip + n + F::READ_SIZE - 1) / F::READ_SIZE * F::READ_SIZE;
+            self.flash
+                .read(addr - skip as u32, &mut scratch[..span])
+                .map_err(|_| FirmwareReadError)?;
+            buf[..n].copy_from_slice(&scratch[skip..][..n]);
+            addr += n as u32;
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::task::{Context, Poll};
+
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_time::Duration;
+
+/// Storage for lines read from the firmware console, shared between the [`Runner`](crate::Runner)
+/// and a [`FirmwareLog`] reader.
+///
+/// `N` is the ring buffer size in bytes. Lines that don't fit while the buffer is full are dropped.
+pub struct FirmwareLogBuffer<const N: usize> {
+    ring: RefCell<Ring<N>>,
+    waker: RefCell<WakerRegistration>,
+    /// How often the runner polls the console, set by the runner.
+    interval: Cell<Duration>,
+}
+
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl<const N: usize> FirmwareLogBuffer<N> {
+    pub fn new() -> Self {
+        Self {
+            ring: RefCell::new(Ring {
+                buf: [0; N],
+                start: 0,
+                len: 0,
+                dropped: 0,
+            }),
+            waker: RefCell::new(WakerRegistration::new()),
+            interval: Cell::new(Duration::from_ticks(0)),
+        }
+    }
+}
+
+pub(crate) trait LogSink {
+    /// Append a complete line, without line terminator.
+    fn push_line(&self, line: &[u8]);
+    /// Pop the oldest line into `buf`, truncating it if needed.
+    fn try_read_line(&self, buf: &mut [u8]) -> Option<usize>;
+    fn poll_read_line(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<usize>;
+    fn dropped(&self) -> usize;
+    fn set_interval(&self, interval: Duration);
+    fn interval(&self) -> Duration;
+}
+
+impl<const N: usize> LogSink for FirmwareLogBuffer<N> {
+    fn push_line(&self, line: &[u8]) {
+        let mut ring = self.ring.borrow_mut();
+
+        // Lines are stored with a `\n` terminator.
+        if ring.len + line.len() + 1 > N {
+            ring.dropped += 1;
+            return;
+        }
+
+        for &b in line.iter().chain([b'\n'].iter()) {
+            let i = (ring.start + ring.len) % N;
+            ring.buf[i] = b;
+            ring.len += 1;
+        }
+
+        self.waker.borrow_mut().wake();
+    }
+
+    fn try_read_line(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut ring = self.ring.borrow_mut();
+        if ring.len == 0 {
+            return None;
+        }
+
+        // Only complete lines are stored, so there's always a terminator.
+        let mut n = 0;
+        loop {
+            let b = ring.buf[ring.start];
+            ring.start = (ring.start + 1) % N;
+            ring.len -= 1;
+            if b == b'\n' {
+                break;
+            }
+            if n < buf.len() {
+                buf[n] = b;
+                n += 1;
+            }
+        }
+
+        Some(n)
+    }
+
+    fn poll_read_line(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<usize> {
+        match self.try_read_line(buf) {
+            Some(n) => Poll::Ready(n),
+            None => {
+                self.waker.borrow_mut().register(cx.waker());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn dropped(&self) -> usize {
+        self.ring.borrow().dropped
+    }
+
+    fn set_interval(&self, interval: Duration) {
+        self.interval.set(interval);
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval.get()
+    }
+}
+
+/// Reader for the WLAN firmware console, created by [`Runner::firmware_log`](crate::Runner::firmware_log).
+pub struct FirmwareLog<'a> {
+    sink: &'a dyn LogSink,
+}
+
+impl<'a> FirmwareLog<'a> {
+    pub(crate) fn new(sink: &'a dyn LogSink) -> Self {
+        Self { sink }
+    }
+
+    /// Wait for the next complete line of the firmware console and copy it into `buf`, without
+    /// line terminator. Lines longer than `buf` are truncated. Returns the number of bytes copied.
+    pub async fn read_line(&mut self, buf: &mut [u8]) -> usize {
+        poll_fn(|cx| self.sink.poll_read_line(cx, buf)).await
+    }
+
+    /// Copy the oldest buffered line into `buf` like [`read_line`](Self::read_line), or return
+    /// `None` if no complete line is buffered.
+    pub fn try_read_line(&mut self, buf: &mut [u8]) -> Option<usize> {
+        self.sink.try_read_line(buf)
+    }
+
+    /// Number of lines dropped so far because the buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.sink.dropped()
+    }
+
+    /// How often the runner polls the firmware console, see
+    /// [`Runner::set_firmware_log_interval`](crate::Runner::set_firmware_log_interval).
+    pub fn interval(&self) -> Duration {
+        self.sink.interval()
+    }
+}
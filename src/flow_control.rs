@@ -0,0 +1,104 @@
+use crate::wmm::AccessCategory;
+
+/// Firmware TX FIFOs, one per WMM access category plus broadcast/multicast and ATIM.
+pub const FIFO_COUNT: usize = 6;
+
+// Signalling TLVs in the BDC header area.
+const TLV_FIFO_CREDITBACK: u8 = 11;
+const TLV_FILLER: u8 = 255;
+
+/// FIFO used for an 802.1D priority. The first FIFOs are indexed by access category.
+pub fn prio_to_fifo(priority: u8) -> usize {
+    AccessCategory::from_priority(priority) as usize
+}
+
+/// Firmware flow control state for data frames. IOCTLs are only subject to the bus credit.
+pub struct FlowControl {
+    /// Flow control bitmap from the SDPCM header. A set bit holds back frames of that 802.1D priority.
+    bitmap: u8,
+    /// Per-FIFO credits, only enforced once the firmware has announced them with a
+    /// `FIFO_CREDIT_MAP` event. It only does so with proptxstatus signalling enabled, which is
+    /// also what returns the credits.
+    credits: Option<[u8; FIFO_COUNT]>,
+    /// Credits from the last `FIFO_CREDIT_MAP` event.
+    init_credits: [u8; FIFO_COUNT],
+}
+
+impl FlowControl {
+    pub fn new() -> Self {
+        Self {
+            bitmap: 0,
+            credits: None,
+            init_credits: [0; FIFO_COUNT],
+        }
+    }
+
+    pub fn update_bitmap(&mut self, bitmap: u8) {
+        if bitmap != self.bitmap {
+            debug!("flow control {:02x} -> {:02x}", self.bitmap, bitmap);
+            self.bitmap = bitmap;
+        }
+    }
+
+    /// Handle a `FIFO_CREDIT_MAP` event, which carries the total credits of each FIFO.
+    pub fn credit_map(&mut self, data: &[u8]) {
+        if data.len() < FIFO_COUNT {
+            warn!("FIFO_CREDIT_MAP too short, len={}", data.len());
+            return;
+        }
+
+        let mut credits = self.credits.unwrap_or([0; FIFO_COUNT]);
+        for i in 0..FIFO_COUNT {
+            // Keep the credits of frames still in flight accounted for.
+            credits[i] = (credits[i] as i16 + data[i] as i16 - self.init_credits[i] as i16).clamp(0, 255) as u8;
+            self.init_credits[i] = data[i];
+        }
+        debug!("fifo credits {:?}", credits);
+        self.credits = Some(credits);
+    }
+
+    /// Parse the signalling TLVs between the BDC header and the packet data, and take back
+    /// returned credits.
+    pub fn signals(&mut self, mut tlvs: &[u8]) {
+        while let Some(&kind) = tlvs.first() {
+            if kind == TLV_FILLER {
+                tlvs = &tlvs[1..];
+                continue;
+            }
+            if tlvs.len() < 2 || tlvs.len() < 2 + tlvs[1] as usize {
+                warn!("truncated signalling TLV");
+                return;
+            }
+            let (value, rest) = tlvs[2..].split_at(tlvs[1] as usize);
+            tlvs = rest;
+
+            if kind == TLV_FIFO_CREDITBACK && value.len() >= FIFO_COUNT {
+                if let Some(credits) = &mut self.credits {
+                    for i in 0..FIFO_COUNT {
+                        credits[i] = credits[i].saturating_add(value[i]).min(self.init_credits[i]);
+                    }
+                    trace!("fifo credits {:?}", credits);
+                }
+            }
+        }
+    }
+
+    /// Whether a data frame of `priority` may be sent now.
+    pub fn can_send(&self, priority: u8) -> bool {
+        if self.bitmap & (1 << (priority & 0x07)) != 0 {
+            return false;
+        }
+        match &self.credits {
+            Some(credits) => credits[prio_to_fifo(priority)] > 0,
+            None => true,
+        }
+    }
+
+    /// Account for a data frame of `priority` being sent.
+    pub fn sent(&mut self, priority: u8) {
+        if let Some(credits) = &mut self.credits {
+            let fifo = prio_to_fifo(priority);
+            credits[fifo] = credits[fifo].saturating_sub(1);
+        }
+    }
+}
@@ -4,6 +4,20 @@ use core::task::{Poll, Waker};
 
 use embassy_sync::waitqueue::WakerRegistration;
 
+/// An ioctl or iovar failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IoctlError {
+    /// The firmware rejected it with this error code, e.g. -23 when unsupported.
+    Firmware(i32),
+    /// The firmware's response is shorter than expected, or not in the expected format.
+    InvalidResponse,
+    /// The request was lost to a bus error before the firmware got it.
+    Lost,
+    /// The firmware didn't respond in time.
+    Timeout,
+}
+
 #[derive(Clone, Copy)]
 pub enum IoctlType {
     Get = 0,
@@ -22,7 +36,7 @@ pub struct PendingIoctl {
 enum IoctlStateInner {
     Pending(PendingIoctl),
     Sent { buf: *mut [u8] },
-    Done { resp_len: usize },
+    Done { result: Result<usize, IoctlError> },
 }
 
 #[derive(Default)]
@@ -39,7 +53,7 @@ pub struct IoctlState {
 impl IoctlState {
     pub fn new() -> Self {
         Self {
-            state: Cell::new(IoctlStateInner::Done { resp_len: 0 }),
+            state: Cell::new(IoctlStateInner::Done { result: Ok(0) }),
             wakers: Default::default(),
         }
     }
@@ -60,10 +74,10 @@ impl IoctlState {
         self.wakers.borrow_mut().runner.register(waker);
     }
 
-    pub async fn wait_complete(&self) -> usize {
+    pub async fn wait_complete(&self) -> Result<usize, IoctlError> {
         poll_fn(|cx| {
-            if let IoctlStateInner::Done { resp_len } = self.state.get() {
-                Poll::Ready(resp_len)
+            if let IoctlStateInner::Done { result } = self.state.get() {
+                Poll::Ready(result)
             } else {
                 self.register_control(cx.waker());
                 Poll::Pending
@@ -88,10 +102,10 @@ impl IoctlState {
     }
 
     pub fn cancel_ioctl(&self) {
-        self.state.set(IoctlStateInner::Done { resp_len: 0 });
+        self.state.set(IoctlStateInner::Done { result: Ok(0) });
     }
 
-    pub async fn do_ioctl(&self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> usize {
+    pub async fn do_ioctl(&self, kind: IoctlType, cmd: u32, iface: u32, buf: &mut [u8]) -> Result<usize, IoctlError> {
         self.state
             .set(IoctlStateInner::Pending(PendingIoctl { buf, kind, cmd, iface }));
         self.wake_runner();
@@ -104,9 +118,16 @@ impl IoctlState {
             (unsafe { &mut *buf }[..response.len()]).copy_from_slice(response);
 
             self.state.set(IoctlStateInner::Done {
-                resp_len: response.len(),
+                result: Ok(response.len()),
             });
             self.wake_control();
         }
     }
+
+    pub fn ioctl_failed(&self, error: IoctlError) {
+        if let IoctlStateInner::Sent { .. } = self.state.get() {
+            self.state.set(IoctlStateInner::Done { result: Err(error) });
+            self.wake_control();
+        }
+    }
 }
@@ -7,111 +7,145 @@
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+mod aggregation;
+mod antenna;
+mod bluetooth;
 mod bus;
+mod chip;
+mod coex;
+#[cfg(feature = "compressed-firmware")]
+mod compress;
+mod config;
 mod consts;
-mod countries;
+pub mod countries;
+mod crash;
+mod crc;
 mod events;
+mod firmware;
+#[cfg(feature = "firmware-logs")]
+mod firmware_log;
+mod flow_control;
 mod ioctl;
+mod sdio;
+mod spi;
 mod structs;
+mod txpower;
+#[cfg(feature = "verify-firmware")]
+mod verify;
+mod wmm;
 
 mod control;
 mod nvram;
+mod phy;
 mod runner;
 
+use core::cell::Cell;
+
 use embassy_net_driver_channel as ch;
 use embedded_hal_1::digital::OutputPin;
 use events::EventQueue;
 use ioctl::IoctlState;
 
+pub use crate::aggregation::{AggregationConfig, AggregationError, MAX_AMPDU_MPDU, MAX_BA_WSIZE};
+pub use crate::antenna::Antenna;
+use crate::bluetooth::BtRunner;
+pub use crate::bluetooth::{BtDriver, BtError, BtState, BT_MAX_PACKET_LEN};
 use crate::bus::Bus;
-pub use crate::bus::SpiBusCyw43;
-pub use crate::control::Control;
+pub use crate::bus::{BusErrors, BusHost};
+pub use crate::coex::{CoexConfig, CoexMode};
+pub use crate::config::{Config, ControlInitError, InitStep, DEFAULT_DISABLED_EVENTS};
+#[cfg(feature = "firmware-logs")]
+pub use crate::control::CONSOLE_QUIET_INTERVALS;
+pub use crate::control::{ConsoleError, Control, CLM_RETRIES, CONSOLE_COMMAND_MAX_LEN};
+use crate::crash::CrashState;
+pub use crate::crash::{AssertInfo, FirmwareCrash, TrapInfo, ASSERT_STR_LEN};
+pub use crate::events::Event;
+#[cfg(feature = "embedded-storage")]
+pub use crate::firmware::FlashSource;
+pub use crate::firmware::{FirmwareReadError, FirmwareSource, FnSource};
+#[cfg(feature = "firmware-logs")]
+pub use crate::firmware_log::{FirmwareLog, FirmwareLogBuffer};
+pub use crate::ioctl::IoctlError;
+pub use crate::nvram::{Nvram, NvramError, NVRAM_MAX_LEN};
+pub use crate::phy::{Band, FixedRate, GMode, NMode, PhyConfig, RateSet, RATE_SET_MAX_LEN};
 pub use crate::runner::Runner;
+pub use crate::sdio::{Sdio, SdioBusCyw43};
+pub use crate::spi::SpiBusCyw43;
+pub use crate::structs::{TrafficStats, WmeCounters};
+pub use crate::txpower::{TxPower, MAX_TX_POWER_DBM};
+pub use crate::wmm::{classify_dscp, AccessCategory, TxClassifier};
 
-const MTU: usize = 1514;
+/// Default MTU, the size of a full Ethernet frame without FCS.
+pub const DEFAULT_MTU: usize = 1514;
 
-#[allow(unused)]
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum Core {
-    WLAN = 0,
-    SOCSRAM = 1,
-    SDIOD = 2,
-}
+/// Default number of packets buffered between the runner and the network stack, per direction.
+pub const DEFAULT_QUEUE_DEPTH: usize = 4;
 
-impl Core {
-    fn base_addr(&self) -> u32 {
-        match self {
-            Self::WLAN => CHIP.arm_core_base_address,
-            Self::SOCSRAM => CHIP.socsram_wrapper_base_address,
-            Self::SDIOD => CHIP.sdiod_core_base_address,
-        }
-    }
-}
+/// Default size of the transfer buffer in 32-bit words. This fits the largest packet the chip can
+/// report on F2 (2047 bytes).
+pub const DEFAULT_BUF_WORDS: usize = 512;
+
+/// Smallest supported transfer buffer in 32-bit words.
+pub const MIN_BUF_WORDS: usize = 256;
+
+/// Most bytes of SDPCM and BDC headers, and padding, in front of a received Ethernet frame.
+pub const MAX_BUS_HEADER_LEN: usize = 64;
 
-#[allow(unused)]
-struct Chip {
-    arm_core_base_address: u32,
-    socsram_base_address: u32,
-    socsram_wrapper_base_address: u32,
-    sdiod_core_base_address: u32,
-    pmu_base_address: u32,
-    chip_ram_size: u32,
-    atcm_ram_base_address: u32,
-    socram_srmem_size: u32,
-    chanspec_band_mask: u32,
-    chanspec_band_2g: u32,
-    chanspec_band_5g: u32,
-    chanspec_band_shift: u32,
-    chanspec_bw_10: u32,
-    chanspec_bw_20: u32,
-    chanspec_bw_40: u32,
-    chanspec_bw_mask: u32,
-    chanspec_bw_shift: u32,
-    chanspec_ctl_sb_lower: u32,
-    chanspec_ctl_sb_upper: u32,
-    chanspec_ctl_sb_none: u32,
-    chanspec_ctl_sb_mask: u32,
-}
-
-const WRAPPER_REGISTER_OFFSET: u32 = 0x100000;
-
-// Data for CYW43439
-const CHIP: Chip = Chip {
-    arm_core_base_address: 0x18003000 + WRAPPER_REGISTER_OFFSET,
-    socsram_base_address: 0x18004000,
-    socsram_wrapper_base_address: 0x18004000 + WRAPPER_REGISTER_OFFSET,
-    sdiod_core_base_address: 0x18002000,
-    pmu_base_address: 0x18000000,
-    chip_ram_size: 512 * 1024,
-    atcm_ram_base_address: 0,
-    socram_srmem_size: 64 * 1024,
-    chanspec_band_mask: 0xc000,
-    chanspec_band_2g: 0x0000,
-    chanspec_band_5g: 0xc000,
-    chanspec_band_shift: 14,
-    chanspec_bw_10: 0x0800,
-    chanspec_bw_20: 0x1000,
-    chanspec_bw_40: 0x1800,
-    chanspec_bw_mask: 0x3800,
-    chanspec_bw_shift: 11,
-    chanspec_ctl_sb_lower: 0x0000,
-    chanspec_ctl_sb_upper: 0x0100,
-    chanspec_ctl_sb_none: 0x0000,
-    chanspec_ctl_sb_mask: 0x0700,
-};
-
-pub struct State {
+/// Driver state, shared between the [`Runner`], [`Control`] and [`NetDriver`].
+///
+/// `MTU` is the largest Ethernet frame exchanged with the network stack. `RX` and `TX` are the
+/// number of `MTU` sized packets queued between the runner and the network stack in each
+/// direction. Deeper RX queues avoid drops under bursty traffic, a depth of 1 saves RAM.
+///
+/// `BUF` is the size in 32-bit words of the transfer buffer the runner uses for every F2 read,
+/// for realigning TX frames that aren't word-aligned, and for reading the firmware log. It must be
+/// at least [`MIN_BUF_WORDS`] and hold a full `MTU` frame with its bus headers, `MTU` plus
+/// [`MAX_BUS_HEADER_LEN`] bytes. Received packets larger than the buffer are dropped.
+///
+/// All large transfer buffers live here. The runner itself only keeps small fixed-size buffers on
+/// its stack: the SDPCM/CDC header words of a frame being sent (28 bytes), one backplane chunk
+/// (64 bytes), and while reading a [`FirmwareCrash`] report, the report and its strings (about
+/// 500 bytes). On top of that come whatever the [`SpiBusCyw43`] or [`SdioBusCyw43`]
+/// implementation's futures need.
+pub struct State<
+    const MTU: usize = DEFAULT_MTU,
+    const RX: usize = DEFAULT_QUEUE_DEPTH,
+    const TX: usize = DEFAULT_QUEUE_DEPTH,
+    const BUF: usize = DEFAULT_BUF_WORDS,
+> {
     ioctl_state: IoctlState,
-    ch: ch::State<MTU, 4, 4>,
+    ch: ch::State<MTU, RX, TX>,
     events: EventQueue,
+    crash: CrashState,
+    bus_errors: Cell<BusErrors>,
+    buf: [u32; BUF],
 }
 
 impl State {
+    /// Create a new `State` with the default MTU, queue depths and transfer buffer size.
     pub fn new() -> Self {
+        Self::new_with_sizes()
+    }
+}
+
+impl<const MTU: usize, const RX: usize, const TX: usize, const BUF: usize> State<MTU, RX, TX, BUF> {
+    const BUF_SIZE_OK: () = ::core::assert!(
+        BUF >= MIN_BUF_WORDS && BUF * 4 >= MTU + MAX_BUS_HEADER_LEN,
+        "transfer buffer too small"
+    );
+
+    /// Create a new `State` with the MTU, queue depths and transfer buffer size given as generic
+    /// parameters, e.g. `State::<1514, 8, 2, 512>::new_with_sizes()`.
+    pub fn new_with_sizes() -> Self {
+        let _ = Self::BUF_SIZE_OK;
+
         Self {
             ioctl_state: IoctlState::new(),
             ch: ch::State::new(),
             events: EventQueue::new(),
+            crash: CrashState::new(),
+            bus_errors: Cell::new(BusErrors::default()),
+            buf: [0; BUF],
         }
     }
 }
@@ -200,28 +234,279 @@ impl PowerManagementMode {
     }
 }
 
-pub type NetDriver<'a> = ch::Device<'a, MTU>;
+/// Stage of the chip bring-up in [`new`] that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InitError {
+    /// The chip did not answer on the bus after power up. It may be unpowered or not connected.
+    BusNotReady,
+    /// The bus test registers read back wrong values.
+    BusTest,
+    /// The ALP clock did not come up.
+    AlpClock,
+    /// The WLAN core did not come out of reset after the firmware upload.
+    CoreNotUp,
+    /// The HT clock did not come up after starting the WLAN core.
+    HtClock,
+    /// The firmware did not become ready for data transfers.
+    FirmwareNotReady,
+    /// The chip ID or its revision is not one of the supported chips.
+    UnknownChip { id: u16, rev: u8 },
+    /// The firmware was built for a different chip.
+    FirmwareMismatch,
+    /// The compressed firmware is truncated or corrupt.
+    FirmwareCorrupt,
+    /// Reading the firmware from its [`FirmwareSource`] failed.
+    FirmwareRead,
+    /// The firmware doesn't match the CRC in its trailer, the image is corrupt. Only checked with
+    /// the `verify-firmware` feature.
+    FirmwareCrc,
+    /// The firmware or NVRAM read back from the chip differs from what was written, there were
+    /// bit errors on the bus. Only checked with the `verify-firmware` feature.
+    FirmwareVerify,
+    /// Bluetooth was requested, but the chip has no Bluetooth on the shared bus.
+    BluetoothUnsupported,
+    /// The Bluetooth firmware patch is malformed.
+    BluetoothFirmware,
+    /// The Bluetooth firmware did not start, or the controller did not wake up.
+    BluetoothNotReady,
+}
+
+impl InitError {
+    /// Whether power cycling the chip and trying again can help. Errors in the firmware images
+    /// themselves would just happen again.
+    fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            Self::FirmwareMismatch
+                | Self::FirmwareCorrupt
+                | Self::FirmwareCrc
+                | Self::BluetoothUnsupported
+                | Self::BluetoothFirmware
+        )
+    }
+}
+
+/// Error downloading the CLM in [`Control::init`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ClmError {
+    /// Reading the CLM from its [`FirmwareSource`] failed.
+    Read,
+    /// The compressed CLM is truncated or corrupt.
+    Corrupt,
+    /// The firmware still reports the download as in progress after the last chunk.
+    InProgress,
+    /// The firmware failed to process a download chunk.
+    IovarError,
+    /// The CLM blob is malformed.
+    BlobFormat,
+    /// The CRC of the CLM blob header is wrong.
+    BlobHeaderCrc,
+    /// The firmware is out of memory for the CLM.
+    BlobNoMemory,
+    /// The CLM blob signature is invalid.
+    BlobSignature,
+    /// The CLM blob is not valid for this firmware.
+    BlobInvalid,
+    /// The firmware reported an unknown `clmload_status`.
+    Unknown(u32),
+}
+
+impl ClmError {
+    /// Decode a `clmload_status`. Returns `None` on success.
+    fn from_status(status: u32) -> Option<Self> {
+        match status {
+            0 => None,
+            1 => Some(Self::InProgress),
+            2 => Some(Self::IovarError),
+            3 => Some(Self::BlobFormat),
+            4 => Some(Self::BlobHeaderCrc),
+            5 => Some(Self::BlobNoMemory),
+            6 => Some(Self::BlobSignature),
+            7 => Some(Self::BlobInvalid),
+            status => Some(Self::Unknown(status)),
+        }
+    }
+}
+
+impl From<FirmwareReadError> for ClmError {
+    fn from(_: FirmwareReadError) -> Self {
+        ClmError::Read
+    }
+}
+
+/// Number of times [`new`] power cycles the chip and retries after a failed bring-up.
+pub const DEFAULT_INIT_RETRIES: u8 = 2;
 
-pub async fn new<'a, PWR, SPI>(
-    state: &'a mut State,
+pub type NetDriver<'a, const MTU: usize = DEFAULT_MTU> = ch::Device<'a, MTU>;
+
+/// Power up the chip, upload `firmware` and `nvram` and start it.
+///
+/// `firmware` is any [`FirmwareSource`], usually a byte slice. `nvram` is the board configuration,
+/// [`Nvram::pico_w`] for the Raspberry Pi Pico W.
+///
+/// With the `compressed-firmware` feature, `firmware` may also be compressed with `cyw43-compress`.
+///
+/// A failed bring-up is retried [`DEFAULT_INIT_RETRIES`] times, see [`new_with_retries`].
+pub async fn new<'a, PWR, BUS, FW, const MTU: usize, const RX: usize, const TX: usize, const BUF: usize>(
+    state: &'a mut State<MTU, RX, TX, BUF>,
+    pwr: PWR,
+    bus: BUS,
+    firmware: FW,
+    nvram: &Nvram,
+) -> Result<(NetDriver<'a, MTU>, Control<'a>, Runner<'a, PWR, BUS, MTU>), InitError>
+where
+    PWR: OutputPin,
+    BUS: BusHost,
+    FW: FirmwareSource,
+{
+    new_with_retries(state, pwr, bus, firmware, nvram, DEFAULT_INIT_RETRIES).await
+}
+
+/// Like [`new`], but power cycle the chip and retry at most `retries` times if the bring-up fails.
+/// Returns the error of the last attempt. Errors in the firmware images, like
+/// [`InitError::FirmwareCrc`], are returned right away.
+pub async fn new_with_retries<'a, PWR, BUS, FW, const MTU: usize, const RX: usize, const TX: usize, const BUF: usize>(
+    state: &'a mut State<MTU, RX, TX, BUF>,
     pwr: PWR,
-    spi: SPI,
-    firmware: &[u8],
-) -> (NetDriver<'a>, Control<'a>, Runner<'a, PWR, SPI>)
+    bus: BUS,
+    firmware: FW,
+    nvram: &Nvram,
+    retries: u8,
+) -> Result<(NetDriver<'a, MTU>, Control<'a>, Runner<'a, PWR, BUS, MTU>), InitError>
 where
     PWR: OutputPin,
-    SPI: SpiBusCyw43,
+    BUS: BusHost,
+    FW: FirmwareSource,
+{
+    init(state, pwr, bus, firmware, nvram, None, retries).await
+}
+
+/// Like [`new`], but also start the Bluetooth controller with `bt_firmware`, the chip's
+/// Bluetooth firmware patch (`43439A0_btfw.bin` for the CYW43439).
+///
+/// HCI packets are exchanged through the returned [`BtDriver`], while the [`Runner`] is running.
+///
+/// A failed bring-up is retried [`DEFAULT_INIT_RETRIES`] times, see [`new_with_bluetooth_and_retries`].
+pub async fn new_with_bluetooth<
+    'a,
+    PWR,
+    BUS,
+    FW,
+    const MTU: usize,
+    const RX: usize,
+    const TX: usize,
+    const BUF: usize,
+>(
+    state: &'a mut State<MTU, RX, TX, BUF>,
+    bt_state: &'a BtState,
+    pwr: PWR,
+    bus: BUS,
+    firmware: FW,
+    nvram: &Nvram,
+    bt_firmware: &[u8],
+) -> Result<(NetDriver<'a, MTU>, BtDriver<'a>, Control<'a>, Runner<'a, PWR, BUS, MTU>), InitError>
+where
+    PWR: OutputPin,
+    BUS: BusHost,
+    FW: FirmwareSource,
+{
+    new_with_bluetooth_and_retries(
+        state,
+        bt_state,
+        pwr,
+        bus,
+        firmware,
+        nvram,
+        bt_firmware,
+        DEFAULT_INIT_RETRIES,
+    )
+    .await
+}
+
+/// Like [`new_with_bluetooth`], but retry at most `retries` times if the bring-up fails, see
+/// [`new_with_retries`].
+#[allow(clippy::too_many_arguments)]
+pub async fn new_with_bluetooth_and_retries<
+    'a,
+    PWR,
+    BUS,
+    FW,
+    const MTU: usize,
+    const RX: usize,
+    const TX: usize,
+    const BUF: usize,
+>(
+    state: &'a mut State<MTU, RX, TX, BUF>,
+    bt_state: &'a BtState,
+    pwr: PWR,
+    bus: BUS,
+    firmware: FW,
+    nvram: &Nvram,
+    bt_firmware: &[u8],
+    retries: u8,
+) -> Result<(NetDriver<'a, MTU>, BtDriver<'a>, Control<'a>, Runner<'a, PWR, BUS, MTU>), InitError>
+where
+    PWR: OutputPin,
+    BUS: BusHost,
+    FW: FirmwareSource,
+{
+    let (device, control, runner) =
+        init(state, pwr, bus, firmware, nvram, Some((bt_state, bt_firmware)), retries).await?;
+    Ok((device, BtDriver::new(bt_state), control, runner))
+}
+
+async fn init<'a, PWR, BUS, FW, const MTU: usize, const RX: usize, const TX: usize, const BUF: usize>(
+    state: &'a mut State<MTU, RX, TX, BUF>,
+    pwr: PWR,
+    bus: BUS,
+    mut firmware: FW,
+    nvram: &Nvram,
+    bt: Option<(&'a BtState, &[u8])>,
+    retries: u8,
+) -> Result<(NetDriver<'a, MTU>, Control<'a>, Runner<'a, PWR, BUS, MTU>), InitError>
+where
+    PWR: OutputPin,
+    BUS: BusHost,
+    FW: FirmwareSource,
 {
     let (ch_runner, device) = ch::new(&mut state.ch, [0; 6]);
     let state_ch = ch_runner.state_runner();
 
-    let mut runner = Runner::new(ch_runner, Bus::new(pwr, spi), &state.ioctl_state, &state.events);
+    let mut runner = Runner::new(
+        ch_runner,
+        Bus::new(pwr, bus.into_transport()),
+        &state.ioctl_state,
+        &state.events,
+        &state.crash,
+        &state.bus_errors,
+        bt.map(|(bt_state, _)| BtRunner::new(bt_state)),
+        &mut state.buf,
+    );
+    let bt_firmware = bt.map(|(_, bt_firmware)| bt_firmware);
 
-    runner.init(firmware).await;
+    let mut attempt = 0;
+    while let Err(e) = runner.init(&mut firmware, nvram.as_bytes(), bt_firmware).await {
+        if attempt == retries || !e.is_retryable() {
+            return Err(e);
+        }
+        attempt += 1;
+        warn!(
+            "init failed: {:?}, power cycling and retrying ({}/{})",
+            e, attempt, retries
+        );
+    }
 
-    (
+    Ok((
         device,
-        Control::new(state_ch, &state.events, &state.ioctl_state),
+        Control::new(
+            state_ch,
+            &state.events,
+            &state.ioctl_state,
+            &state.crash,
+            &state.bus_errors,
+        ),
         runner,
-    )
+    ))
 }
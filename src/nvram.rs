@@ -1,10 +1,15 @@
+//! The NVRAM is the board configuration of the chip: crystal frequency, antenna gains, PA
+//! calibration and so on. It is uploaded with the firmware as `key=value` entries, each followed
+//! by a NUL, and ends with an extra NUL.
+
 macro_rules! nvram {
     ($($s:literal,)*) => {
         concat_bytes!($($s, b"\x00",)* b"\x00\x00")
     };
 }
 
-pub static NVRAM: &'static [u8] = &*nvram!(
+/// NVRAM of the Raspberry Pi Pico W.
+static NVRAM: &'static [u8] = &*nvram!(
     b"NVRAMRev=$Rev$",
     b"manfid=0x2d0",
     b"prodid=0x0727",
@@ -52,3 +57,138 @@ pub static NVRAM: &'static [u8] = &*nvram!(
     b"glitch_based_crsmin=1",
     b"btc_mode=1",
 );
+
+/// Maximum size of an [`Nvram`], in bytes.
+pub const NVRAM_MAX_LEN: usize = 2048;
+
+/// Error building or parsing an [`Nvram`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NvramError {
+    /// The entries don't fit in [`NVRAM_MAX_LEN`] bytes.
+    Full,
+    /// A key is empty or contains `=`, whitespace or NUL, or a value contains NUL.
+    InvalidEntry,
+    /// Line `n`, counting from 1, of a text NVRAM isn't a `key=value` pair, a comment or empty.
+    Syntax(usize),
+}
+
+/// Board configuration uploaded to the chip, see [`new`](crate::new).
+///
+/// Start from [`Nvram::pico_w`] and override the values that differ on your board with
+/// [`set`](Self::set), or parse the board's `.txt` NVRAM file with [`parse`](Self::parse).
+#[derive(Clone)]
+pub struct Nvram {
+    /// The entries, then zeros.
+    buf: [u8; NVRAM_MAX_LEN],
+    /// Length of the entries. There is always room for the final NUL after them.
+    len: usize,
+}
+
+impl Nvram {
+    /// An NVRAM without entries.
+    pub const fn empty() -> Self {
+        Self {
+            buf: [0; NVRAM_MAX_LEN],
+            len: 0,
+        }
+    }
+
+    /// The NVRAM of the Raspberry Pi Pico W, for the CYW43439 with its PCB antenna and a 37.4 MHz crystal.
+    pub fn pico_w() -> Self {
+        // Drop the padding NULs, after the NUL ending the last entry.
+        let entries = &NVRAM[..NVRAM.len() - 2];
+        let mut nvram = Self::empty();
+        nvram.buf[..entries.len()].copy_from_slice(entries);
+        nvram.len = entries.len();
+        nvram
+    }
+
+    /// Parse an NVRAM in the usual text format: `key=value` lines, `#` comment lines and empty
+    /// lines. Leading and trailing whitespace is ignored.
+    pub fn parse(text: &str) -> Result<Self, NvramError> {
+        let mut nvram = Self::empty();
+        nvram.extend_from_text(text)?;
+        Ok(nvram)
+    }
+
+    /// Set the entries of a text NVRAM, see [`parse`](Self::parse), overriding existing keys.
+    pub fn extend_from_text(&mut self, text: &str) -> Result<(), NvramError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or(NvramError::Syntax(i + 1))?;
+            match self.set(key.trim_end(), value.trim_start()) {
+                Err(NvramError::InvalidEntry) => return Err(NvramError::Syntax(i + 1)),
+                r => r?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Set `key` to `value`, replacing the existing entry if there is one.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), NvramError> {
+        let key_ok = !key.is_empty() && !key.bytes().any(|b| b == b'=' || b == 0 || b.is_ascii_whitespace());
+        if !key_ok || value.bytes().any(|b| b == 0) {
+            return Err(NvramError::InvalidEntry);
+        }
+
+        let entry_len = key.len() + 1 + value.len() + 1;
+        let old_len = self.find(key).map_or(0, |(start, end)| end - start);
+        if self.len - old_len + entry_len >= NVRAM_MAX_LEN {
+            return Err(NvramError::Full);
+        }
+
+        self.remove(key);
+        let entry = &mut self.buf[self.len..][..entry_len];
+        entry[..key.len()].copy_from_slice(key.as_bytes());
+        entry[key.len()] = b'=';
+        entry[key.len() + 1..][..value.len()].copy_from_slice(value.as_bytes());
+        entry[entry_len - 1] = 0;
+        self.len += entry_len;
+        Ok(())
+    }
+
+    /// Remove the entry for `key`, if there is one.
+    pub fn remove(&mut self, key: &str) {
+        if let Some((start, end)) = self.find(key) {
+            self.buf.copy_within(end..self.len, start);
+            self.buf[self.len - (end - start)..self.len].fill(0);
+            self.len -= end - start;
+        }
+    }
+
+    /// Value of `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let (start, end) = self.find(key)?;
+        core::str::from_utf8(&self.buf[start + key.len() + 1..end - 1]).ok()
+    }
+
+    /// The NVRAM as uploaded to the chip.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len + 1]
+    }
+
+    /// Position of the entry for `key`, including its NUL.
+    fn find(&self, key: &str) -> Option<(usize, usize)> {
+        let mut start = 0;
+        while start < self.len {
+            let end = start + unwrap!(self.buf[start..self.len].iter().position(|&b| b == 0)) + 1;
+            let entry = &self.buf[start..end];
+            if entry.len() > key.len() && entry.starts_with(key.as_bytes()) && entry[key.len()] == b'=' {
+                return Some((start, end));
+            }
+            start = end;
+        }
+        None
+    }
+}
+
+impl Default for Nvram {
+    /// The NVRAM of the Raspberry Pi Pico W, see [`Nvram::pico_w`].
+    fn default() -> Self {
+        Self::pico_w()
+    }
+}
@@ -0,0 +1,189 @@
+/// 802.11g mode, the firmware's `gmode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GMode {
+    /// 802.11b only.
+    LegacyB = 0,
+    /// 802.11b and 802.11g.
+    Auto = 1,
+    /// 802.11g only, 802.11b clients can't associate.
+    GOnly = 2,
+    /// Like `Auto`, but 802.11b rates are only used after 802.11g ones failed.
+    BDeferred = 3,
+    /// 802.11g without protection for 802.11b stations, for the best throughput.
+    Performance = 4,
+    /// Like `Auto`, limited to the long range rates.
+    Lrs = 5,
+}
+
+impl GMode {
+    pub(crate) fn from_u32(gmode: u32) -> Option<Self> {
+        match gmode {
+            0 => Some(Self::LegacyB),
+            1 => Some(Self::Auto),
+            2 => Some(Self::GOnly),
+            3 => Some(Self::BDeferred),
+            4 => Some(Self::Performance),
+            5 => Some(Self::Lrs),
+            _ => None,
+        }
+    }
+}
+
+/// Frequency band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Band {
+    /// Any band the chip supports.
+    Auto = 0,
+    /// 5 GHz only.
+    Band5G = 1,
+    /// 2.4 GHz only.
+    Band2G = 2,
+}
+
+impl Band {
+    pub(crate) fn from_u32(band: u32) -> Option<Self> {
+        match band {
+            0 => Some(Self::Auto),
+            1 => Some(Self::Band5G),
+            2 => Some(Self::Band2G),
+            _ => None,
+        }
+    }
+}
+
+/// 802.11n mode, the firmware's `nmode` and `nreqd`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NMode {
+    /// 802.11n rates are not used.
+    Disabled,
+    /// 802.11n rates are used with peers supporting them.
+    Enabled,
+    /// Only peers supporting 802.11n can associate.
+    Required,
+}
+
+/// Maximum number of rates in a [`RateSet`].
+pub const RATE_SET_MAX_LEN: usize = 16;
+
+/// Legacy rates, in 500 kbit/s units, e.g. 2 for 1 Mbit/s or 108 for 54 Mbit/s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RateSet {
+    len: u8,
+    rates: [u8; RATE_SET_MAX_LEN],
+}
+
+impl RateSet {
+    /// Flag of rates stations must support to associate.
+    const BASIC: u8 = 0x80;
+
+    /// A rate set with the `supported` rates, of which the `basic` ones are required to associate.
+    /// Panics if there are more than [`RATE_SET_MAX_LEN`] rates.
+    pub fn new(supported: &[u8], basic: &[u8]) -> Self {
+        assert!(supported.len() <= RATE_SET_MAX_LEN);
+        let mut rates = [0; RATE_SET_MAX_LEN];
+        for (r, &rate) in rates.iter_mut().zip(supported) {
+            *r = rate & !Self::BASIC;
+            if basic.contains(r) {
+                *r |= Self::BASIC;
+            }
+        }
+        Self {
+            len: supported.len() as u8,
+            rates,
+        }
+    }
+
+    /// Supported rates.
+    pub fn supported(&self) -> impl Iterator<Item = u8> + '_ {
+        self.rates[..self.len as usize].iter().map(|&r| r & !Self::BASIC)
+    }
+
+    /// Basic rates, required to associate.
+    pub fn basic(&self) -> impl Iterator<Item = u8> + '_ {
+        self.rates[..self.len as usize]
+            .iter()
+            .filter(|&&r| r & Self::BASIC != 0)
+            .map(|&r| r & !Self::BASIC)
+    }
+
+    /// The firmware's `wl_rateset_t`: the count as a `u32`, then the rates.
+    pub(crate) fn to_bytes(&self) -> [u8; 4 + RATE_SET_MAX_LEN] {
+        let mut buf = [0; 4 + RATE_SET_MAX_LEN];
+        buf[..4].copy_from_slice(&(self.len as u32).to_le_bytes());
+        buf[4..].copy_from_slice(&self.rates);
+        buf
+    }
+
+    pub(crate) fn from_bytes(buf: &[u8; 4 + RATE_SET_MAX_LEN]) -> Self {
+        let len = u32::from_le_bytes(buf[..4].try_into().unwrap()).min(RATE_SET_MAX_LEN as u32);
+        Self {
+            len: len as u8,
+            rates: buf[4..].try_into().unwrap(),
+        }
+    }
+}
+
+/// Fixed transmit rate, instead of the firmware's rate selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FixedRate {
+    /// A legacy 802.11b/g rate, in 500 kbit/s units.
+    Legacy(u8),
+    /// An 802.11n MCS index.
+    Mcs(u8),
+}
+
+impl FixedRate {
+    /// Rate spec encoding 802.11n MCS indices.
+    const RSPEC_ENCODE_HT: u32 = 0x0100_0000;
+    const RSPEC_ENCODING_MASK: u32 = 0x0300_0000;
+
+    /// The firmware's rate spec, as used by `2g_rate`.
+    pub(crate) fn to_ratespec(self) -> u32 {
+        match self {
+            Self::Legacy(rate) => rate as u32,
+            Self::Mcs(mcs) => Self::RSPEC_ENCODE_HT | mcs as u32,
+        }
+    }
+
+    /// `None` for automatic rate selection, or an encoding unknown to this driver.
+    pub(crate) fn from_ratespec(ratespec: u32) -> Option<Self> {
+        if ratespec == 0 {
+            return None;
+        }
+        match ratespec & Self::RSPEC_ENCODING_MASK {
+            0 => Some(Self::Legacy(ratespec as u8)),
+            Self::RSPEC_ENCODE_HT => Some(Self::Mcs(ratespec as u8)),
+            _ => None,
+        }
+    }
+}
+
+/// 802.11 PHY configuration, see [`Control::set_phy`](crate::Control::set_phy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PhyConfig {
+    pub gmode: GMode,
+    pub band: Band,
+    pub nmode: NMode,
+    /// Rates to use. `None` keeps the firmware's rate set for the band.
+    pub rates: Option<RateSet>,
+    /// Rate to always transmit at. `None` lets the firmware pick the rate.
+    pub fixed_rate: Option<FixedRate>,
+}
+
+impl Default for PhyConfig {
+    fn default() -> Self {
+        Self {
+            gmode: GMode::Auto,
+            band: Band::Auto,
+            nmode: NMode::Enabled,
+            rates: None,
+            fixed_rate: None,
+        }
+    }
+}
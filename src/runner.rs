@@ -1,131 +1,250 @@
+use core::cell::Cell;
+use core::future::pending;
 use core::slice;
 
-use embassy_futures::select::{select3, Either3};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_net_driver_channel as ch;
 use embassy_sync::pubsub::PubSubBehavior;
-use embassy_time::{block_for, Duration, Timer};
+use embassy_time::{block_for, Duration, Instant, Timer};
 use embedded_hal_1::digital::OutputPin;
 
-use crate::bus::Bus;
-pub use crate::bus::SpiBusCyw43;
+use crate::bluetooth::BtRunner;
+use crate::bus::{Bus, BusErrors, BusHost};
+use crate::chip::{ArmCore, Chip, Core, CHIPCOMMON_CHIP_ID_ADDRESS, CYW43439};
+#[cfg(feature = "compressed-firmware")]
+use crate::compress::{self, Decompressor};
 use crate::consts::*;
+use crate::crash::{AssertInfo, CrashState, FirmwareCrash, ASSERT_STR_LEN};
+#[cfg(feature = "verify-firmware")]
+use crate::crc::Crc32;
 use crate::events::{EventQueue, EventStatus};
+use crate::firmware::FirmwareSource;
+#[cfg(feature = "firmware-logs")]
+use crate::firmware_log::{FirmwareLog, FirmwareLogBuffer, LogSink};
+use crate::flow_control::FlowControl;
+#[cfg(feature = "firmware-logs")]
+use crate::fmt::utf8_prefix;
 use crate::fmt::Bytes;
-use crate::ioctl::{IoctlState, IoctlType, PendingIoctl};
-use crate::nvram::NVRAM;
+use crate::ioctl::{IoctlError, IoctlState, IoctlType, PendingIoctl};
 use crate::structs::*;
-use crate::{events, Core, CHIP, MTU};
+#[cfg(feature = "verify-firmware")]
+use crate::verify;
+use crate::wmm::{classify_dscp, TxClassifier};
+use crate::{events, InitError, DEFAULT_MTU};
 
 #[cfg(feature = "firmware-logs")]
-struct LogState {
+struct LogState<'a> {
     addr: u32,
     last_idx: usize,
     buf: [u8; 256],
     buf_count: usize,
+    read_at: Instant,
+    interval: Duration,
+    sink: Option<&'a dyn LogSink>,
 }
 
 #[cfg(feature = "firmware-logs")]
-impl Default for LogState {
+impl Default for LogState<'_> {
     fn default() -> Self {
         Self {
             addr: Default::default(),
             last_idx: Default::default(),
             buf: [0; 256],
             buf_count: Default::default(),
+            read_at: Instant::MAX,
+            interval: DEFAULT_LOG_INTERVAL,
+            sink: None,
         }
     }
 }
 
-pub struct Runner<'a, PWR, SPI> {
+/// Default interval for polling the firmware console.
+#[cfg(feature = "firmware-logs")]
+const DEFAULT_LOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long to wait for each chip clock to come up during init.
+const CLOCK_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long to wait for the firmware to become ready for F2 transfers after starting the WLAN core.
+const FIRMWARE_READY_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// How often the runner checks the firmware's shared memory for a crash.
+const CRASH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Subframe lengths announced by a glom descriptor, for the superframe that follows it.
+struct GlomDesc {
+    lens: [u16; SDPCM_MAX_GLOM],
+    count: usize,
+}
+
+pub struct Runner<'a, PWR, BUS: BusHost, const MTU: usize = DEFAULT_MTU> {
     ch: ch::Runner<'a, MTU>,
-    bus: Bus<PWR, SPI>,
+    bus: Bus<PWR, BUS::Transport>,
+
+    /// Detected at init.
+    chip: &'static Chip,
 
     ioctl_state: &'a IoctlState,
     ioctl_id: u16,
+    /// The last frame written to F2 was an ioctl.
+    last_tx_ioctl: bool,
     sdpcm_seq: u8,
     sdpcm_seq_max: u8,
+    /// Expected sequence number of the next received frame, `None` until resynchronized.
+    rx_seq: Option<u8>,
+    flow: FlowControl,
+    classify: TxClassifier,
+    glom: Option<GlomDesc>,
 
     events: &'a EventQueue,
 
+    crash: &'a CrashState,
+    /// Address of the firmware's shared memory area, once the firmware has published it.
+    shared_addr: Option<u32>,
+    crash_check_at: Instant,
+
+    bus_errors: &'a Cell<BusErrors>,
+
+    /// Bluetooth on the shared bus, if enabled.
+    bt: Option<BtRunner<'a>>,
+
+    /// Transfer buffer from `State`, taken by `run`.
+    buf: &'a mut [u32],
+
     #[cfg(feature = "firmware-logs")]
-    log: LogState,
+    log: LogState<'a>,
 }
 
-impl<'a, PWR, SPI> Runner<'a, PWR, SPI>
+impl<'a, PWR, BUS, const MTU: usize> Runner<'a, PWR, BUS, MTU>
 where
     PWR: OutputPin,
-    SPI: SpiBusCyw43,
+    BUS: BusHost,
 {
     pub(crate) fn new(
         ch: ch::Runner<'a, MTU>,
-        bus: Bus<PWR, SPI>,
+        bus: Bus<PWR, BUS::Transport>,
         ioctl_state: &'a IoctlState,
         events: &'a EventQueue,
+        crash: &'a CrashState,
+        bus_errors: &'a Cell<BusErrors>,
+        bt: Option<BtRunner<'a>>,
+        buf: &'a mut [u32],
     ) -> Self {
         Self {
             ch,
             bus,
+            chip: &CYW43439,
             ioctl_state,
             ioctl_id: 0,
+            last_tx_ioctl: false,
             sdpcm_seq: 0,
             sdpcm_seq_max: 1,
+            rx_seq: None,
+            flow: FlowControl::new(),
+            classify: classify_dscp,
+            glom: None,
             events,
+            crash,
+            shared_addr: None,
+            crash_check_at: Instant::MAX,
+            bus_errors,
+            bt,
+            buf,
             #[cfg(feature = "firmware-logs")]
             log: LogState::default(),
         }
     }
 
-    pub(crate) async fn init(&mut self, firmware: &[u8]) {
-        self.bus.init().await;
+    pub(crate) async fn init<FW: FirmwareSource>(
+        &mut self,
+        firmware: &mut FW,
+        nvram: &[u8],
+        bt_firmware: Option<&[u8]>,
+    ) -> Result<(), InitError> {
+        self.bus.init().await?;
 
         // Init ALP (Active Low Power) clock
         self.bus
             .write8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR, BACKPLANE_ALP_AVAIL_REQ)
             .await;
         info!("waiting for clock...");
-        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & BACKPLANE_ALP_AVAIL == 0 {}
+        let deadline = Instant::now() + CLOCK_TIMEOUT;
+        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & BACKPLANE_ALP_AVAIL == 0 {
+            if Instant::now() > deadline {
+                return Err(InitError::AlpClock);
+            }
+        }
         info!("clock ok");
 
-        let chip_id = self.bus.bp_read16(0x1800_0000).await;
-        info!("chip ID: {}", chip_id);
+        let chip_id = self.bus.bp_read32(CHIPCOMMON_CHIP_ID_ADDRESS).await;
+        let (id, rev) = (chip_id as u16, (chip_id >> 16) as u8 & 0xf);
+        info!("chip ID: {} rev {}", id, rev);
+
+        self.chip = Chip::from_id(id, rev).ok_or(InitError::UnknownChip { id, rev })?;
 
         // Upload firmware.
         self.core_disable(Core::WLAN).await;
-        self.core_reset(Core::SOCSRAM).await;
-        self.bus.bp_write32(CHIP.socsram_base_address + 0x10, 3).await;
-        self.bus.bp_write32(CHIP.socsram_base_address + 0x44, 0).await;
+        if self.chip.arm_core == ArmCore::CM3 {
+            self.core_reset(Core::SOCSRAM).await;
+            self.bus.bp_write32(self.chip.socsram_base_address + 0x10, 3).await;
+            self.bus.bp_write32(self.chip.socsram_base_address + 0x44, 0).await;
+        }
 
-        let ram_addr = CHIP.atcm_ram_base_address;
+        let ram_addr = self.chip.atcm_ram_base_address;
 
         info!("loading fw");
-        self.bus.bp_write(ram_addr, firmware).await;
+        let reset_vector = self.load_firmware(ram_addr, firmware).await?;
 
         info!("loading nvram");
         // Round up to 4 bytes.
-        let nvram_len = (NVRAM.len() + 3) / 4 * 4;
-        self.bus
-            .bp_write(ram_addr + CHIP.chip_ram_size - 4 - nvram_len as u32, NVRAM)
-            .await;
+        let nvram_len = (nvram.len() + 3) / 4 * 4;
+        let nvram_addr = self.chip.nvram_address(nvram_len);
+        self.bus.bp_write(nvram_addr, nvram).await;
 
         let nvram_len_words = nvram_len as u32 / 4;
         let nvram_len_magic = (!nvram_len_words << 16) | nvram_len_words;
         self.bus
-            .bp_write32(ram_addr + CHIP.chip_ram_size - 4, nvram_len_magic)
+            .bp_write32(self.chip.nvram_token_address(), nvram_len_magic)
             .await;
 
+        #[cfg(feature = "verify-firmware")]
+        {
+            let mut crc = Crc32::new();
+            crc.update(nvram);
+            let (readback_crc, _) = self.readback_crc(nvram_addr, nvram.len(), 0).await;
+            let readback_magic = self.bus.bp_read32(self.chip.nvram_token_address()).await;
+            if readback_crc != crc.finish() || readback_magic != nvram_len_magic {
+                return Err(InitError::FirmwareVerify);
+            }
+        }
+
+        if self.chip.arm_core == ArmCore::CR4 {
+            // The CR4 starts at the reset vector at address 0, which is the first word of the firmware.
+            self.bus.bp_write32(0, reset_vector).await;
+        }
+
         // Start core!
         info!("starting up core...");
         self.core_reset(Core::WLAN).await;
-        assert!(self.core_is_up(Core::WLAN).await);
+        if !self.core_is_up(Core::WLAN).await {
+            return Err(InitError::CoreNotUp);
+        }
 
-        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & 0x80 == 0 {}
+        let deadline = Instant::now() + CLOCK_TIMEOUT;
+        while self.bus.read8(FUNC_BACKPLANE, REG_BACKPLANE_CHIP_CLOCK_CSR).await & 0x80 == 0 {
+            if Instant::now() > deadline {
+                return Err(InitError::HtClock);
+            }
+        }
 
-        // "Set up the interrupt mask and enable interrupts"
-        // self.bus.bp_write32(CHIP.sdiod_core_base_address + 0x24, 0xF0).await;
+        let mut irq = IRQ_F2_PACKET_AVAILABLE | IRQ_BUS_ERRORS;
+        if let (Some(bt), Some(bt_firmware)) = (&mut self.bt, bt_firmware) {
+            bt.init(&mut self.bus, self.chip, bt_firmware).await?;
+            irq |= IRQ_F1_INTR;
+        }
 
-        self.bus
-            .write16(FUNC_BUS, REG_BUS_INTERRUPT_ENABLE, IRQ_F2_PACKET_AVAILABLE)
-            .await;
+        // Set up the interrupt mask and enable interrupts.
+        self.bus.enable_interrupts(self.chip, irq).await;
 
         // "Lower F2 Watermark to avoid DMA Hang in F2 when SD Clock is stopped."
         // Sounds scary...
@@ -135,7 +254,12 @@ where
 
         // wait for wifi startup
         info!("waiting for wifi init...");
-        while self.bus.read32(FUNC_BUS, REG_BUS_STATUS).await & STATUS_F2_RX_READY == 0 {}
+        let deadline = Instant::now() + FIRMWARE_READY_TIMEOUT;
+        while !self.bus.wlan_ready().await {
+            if Instant::now() > deadline {
+                return Err(InitError::FirmwareNotReady);
+            }
+        }
 
         // Some random configs related to sleep.
         // These aren't needed if we don't want to sleep the bus.
@@ -167,89 +291,392 @@ where
         #[cfg(feature = "firmware-logs")]
         self.log_init().await;
 
+        self.crash_check_at = Instant::now() + CRASH_CHECK_INTERVAL;
+
         info!("init done ");
+        Ok(())
     }
 
-    #[cfg(feature = "firmware-logs")]
-    async fn log_init(&mut self) {
-        // Initialize shared memory for logging.
+    /// Check `firmware` against the chip and write it to RAM at `addr`. Returns its first word.
+    async fn load_firmware<FW: FirmwareSource>(&mut self, addr: u32, firmware: &mut FW) -> Result<u32, InitError> {
+        #[cfg(feature = "compressed-firmware")]
+        if compress::is_compressed(firmware).await? {
+            return self.load_compressed_firmware(addr, firmware).await;
+        }
 
-        let addr = CHIP.atcm_ram_base_address + CHIP.chip_ram_size - 4 - CHIP.socram_srmem_size;
-        let shared_addr = self.bus.bp_read32(addr).await;
-        info!("shared_addr {:08x}", shared_addr);
+        // Staged through the transfer buffer, it is free until `run` takes it.
+        let buf = slice8_mut(self.buf);
+        let len = firmware.len();
+
+        // The version string is in the last kilobyte.
+        let tail_len = len.min(1024);
+        firmware.read(len - tail_len, &mut buf[..tail_len]).await?;
+        check_firmware(self.chip, &buf[..tail_len])?;
+        #[cfg(feature = "verify-firmware")]
+        let trailer = verify::trailer_crc(&buf[..tail_len]).map(|(pos, crc)| (len - tail_len + pos, crc));
+        #[cfg(feature = "verify-firmware")]
+        let mut crc = Crc32::new();
+
+        let mut first = 0;
+        let mut offs = 0;
+        while offs < len {
+            let n = (len - offs).min(buf.len());
+            firmware.read(offs, &mut buf[..n]).await?;
+            if offs == 0 {
+                first = first_word(&buf[..n]);
+            }
+            #[cfg(feature = "verify-firmware")]
+            crc.update(&buf[..n]);
+            self.bus.bp_write(addr + offs as u32, &buf[..n]).await;
+            offs += n;
+        }
+
+        #[cfg(feature = "verify-firmware")]
+        self.verify_firmware(addr, len, crc.finish(), trailer).await?;
+        Ok(first)
+    }
+
+    /// Like `load_firmware`, decompressing the firmware chunk by chunk on the way.
+    #[cfg(feature = "compressed-firmware")]
+    async fn load_compressed_firmware<FW: FirmwareSource>(
+        &mut self,
+        addr: u32,
+        firmware: &mut FW,
+    ) -> Result<u32, InitError> {
+        let mut firmware = Decompressor::new(firmware).await?;
+        #[cfg(feature = "verify-firmware")]
+        let mut crc = Crc32::new();
+        let mut reset_vector = None;
+        let mut offs = 0;
+        while let Some(chunk) = firmware.next_chunk().await? {
+            reset_vector.get_or_insert_with(|| first_word(chunk));
+            #[cfg(feature = "verify-firmware")]
+            crc.update(chunk);
+            self.bus.bp_write(addr + offs as u32, chunk).await;
+            offs += chunk.len();
+        }
+
+        // The version string is near the end, so the firmware can only be checked once it is loaded.
+        // It doesn't run until the WLAN core is started.
+        let tail = firmware.tail();
+        check_firmware(self.chip, tail)?;
+
+        #[cfg(feature = "verify-firmware")]
+        {
+            let trailer = verify::trailer_crc(tail).map(|(pos, crc)| (offs - tail.len() + pos, crc));
+            self.verify_firmware(addr, offs, crc.finish(), trailer).await?;
+        }
+        Ok(reset_vector.unwrap_or(0))
+    }
+
+    /// Read back the `len` bytes of firmware at `addr` and check them against `crc`, the CRC of
+    /// what was written, and against the image length and CRC from the firmware's `trailer`.
+    #[cfg(feature = "verify-firmware")]
+    async fn verify_firmware(
+        &mut self,
+        addr: u32,
+        len: usize,
+        crc: u32,
+        trailer: Option<(usize, u32)>,
+    ) -> Result<(), InitError> {
+        info!("verifying fw");
+        let image_len = trailer.map_or(0, |(image_len, _)| image_len);
+        let (readback_crc, image_crc) = self.readback_crc(addr, len, image_len).await;
+
+        if readback_crc != crc {
+            return Err(InitError::FirmwareVerify);
+        }
+        match trailer {
+            Some((_, expected)) if image_crc != expected => Err(InitError::FirmwareCrc),
+            Some(_) => Ok(()),
+            None => {
+                warn!("no CRC in firmware trailer, can't validate the firmware image");
+                Ok(())
+            }
+        }
+    }
+
+    /// Read back `len` bytes at `addr`. Returns their CRC, and the CRC of the first `prefix_len` bytes.
+    #[cfg(feature = "verify-firmware")]
+    async fn readback_crc(&mut self, addr: u32, len: usize, prefix_len: usize) -> (u32, u32) {
+        let buf = slice8_mut(self.buf);
+        let mut crc = Crc32::new();
+        let mut prefix_crc = Crc32::new();
+
+        let mut offs = 0;
+        while offs < len {
+            let n = (len - offs).min(buf.len());
+            self.bus.bp_read(addr + offs as u32, &mut buf[..n]).await;
+            crc.update(&buf[..n]);
+            if offs < prefix_len {
+                prefix_crc.update(&buf[..n.min(prefix_len - offs)]);
+            }
+            offs += n;
+        }
+        (crc.finish(), prefix_crc.finish())
+    }
+
+    /// Read the firmware's shared memory area. Returns `None` if the firmware hasn't published it yet.
+    async fn read_shared(&mut self) -> Option<SharedMemData> {
+        let shared_addr = match self.shared_addr {
+            Some(shared_addr) => shared_addr,
+            None => {
+                let shared_addr = self.bus.bp_read32(self.chip.shared_pointer_address()).await;
+
+                // Before the firmware writes the address, this holds zero or the NVRAM length token.
+                if shared_addr == 0 || !shared_addr >> 16 == shared_addr & 0xffff {
+                    debug!("shared memory not published yet: {:08x}", shared_addr);
+                    return None;
+                }
+
+                info!("shared_addr {:08x}", shared_addr);
+                self.shared_addr = Some(shared_addr);
+                shared_addr
+            }
+        };
 
         let mut shared = [0; SharedMemData::SIZE];
         self.bus.bp_read(shared_addr, &mut shared).await;
-        let shared = SharedMemData::from_bytes(&shared);
+        Some(SharedMemData::from_bytes(&shared))
+    }
+
+    /// Check the firmware's shared memory for a trap or a failed assert, and report it to `Control`.
+    async fn check_crash(&mut self) {
+        self.crash_check_at = Instant::now() + CRASH_CHECK_INTERVAL;
+
+        let Some(shared) = self.read_shared().await else {
+            return;
+        };
+        if shared.flags & (SHARED_TRAP | SHARED_ASSERT) == 0 {
+            return;
+        }
+
+        let trap = if shared.flags & SHARED_TRAP != 0 {
+            let mut trap = [0; TrapInfo::SIZE];
+            self.bus.bp_read(shared.trap_addr, &mut trap).await;
+            Some(TrapInfo::from_bytes(&trap))
+        } else {
+            None
+        };
+
+        let assert = if shared.flags & SHARED_ASSERT != 0 {
+            let mut expr = [0; ASSERT_STR_LEN];
+            let expr_len = self.read_str(shared.assert_exp_addr, &mut expr).await;
+            let mut file = [0; ASSERT_STR_LEN];
+            let file_len = self.read_str(shared.assert_file_addr, &mut file).await;
+            Some(AssertInfo::new(
+                shared.assert_line,
+                &expr[..expr_len],
+                &file[..file_len],
+            ))
+        } else {
+            None
+        };
+
+        let crash = FirmwareCrash {
+            flags: shared.flags,
+            trap,
+            assert,
+        };
+        error!("firmware crashed: {:?}", crash);
+        self.crash.crashed(crash);
+
+        // The firmware doesn't recover by itself, no need to check again.
+        self.crash_check_at = Instant::MAX;
+    }
+
+    /// Read a NUL-terminated string from the backplane into `buf`. Returns its length.
+    async fn read_str(&mut self, addr: u32, buf: &mut [u8; ASSERT_STR_LEN]) -> usize {
+        if addr == 0 {
+            return 0;
+        }
+
+        // `bp_read` needs a 4-aligned address.
+        let offs = (addr & 3) as usize;
+        let mut raw = [0; ASSERT_STR_LEN + 4];
+        self.bus.bp_read(addr & !3, &mut raw[..offs + ASSERT_STR_LEN]).await;
+
+        let raw = &raw[offs..][..ASSERT_STR_LEN];
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        buf[..len].copy_from_slice(&raw[..len]);
+        len
+    }
+
+    #[cfg(feature = "firmware-logs")]
+    async fn log_init(&mut self) {
+        // Initialize shared memory for logging.
+        let Some(shared) = self.read_shared().await else {
+            warn!("firmware shared memory not available, no firmware logs");
+            return;
+        };
 
         self.log.addr = shared.console_addr + 8;
+        self.log.read_at = Instant::now();
     }
 
     #[cfg(feature = "firmware-logs")]
-    async fn log_read(&mut self) {
+    async fn log_read(&mut self, buf: &mut [u32]) {
+        self.log.read_at = Instant::now() + self.log.interval;
+
+        if self.log.addr == 0 {
+            return;
+        }
+
         // Read log struct
         let mut log = [0; SharedMemLog::SIZE];
         self.bus.bp_read(self.log.addr, &mut log).await;
         let log = SharedMemLog::from_bytes(&log);
 
+        let size = log.buf_size as usize;
         let idx = log.idx as usize;
-
-        // If pointer hasn't moved, no need to do anything.
-        if idx == self.log.last_idx {
+        if idx >= size || self.log.last_idx >= size {
+            warn!("firmware log index out of range, idx={} size={}", idx, size);
+            self.log.last_idx = idx;
             return;
         }
 
-        // Read entire buf for now. We could read only what we need, but then we
-        // run into annoying alignment issues in `bp_read`.
-        let mut buf = [0; 0x400];
-        self.bus.bp_read(log.buf, &mut buf).await;
+        // Read the new part of the ring, in chunks that fit the transfer buffer.
+        let buf = slice8_mut(buf);
+        while self.log.last_idx != idx {
+            let start = self.log.last_idx;
+            let end = if idx > start { idx } else { size };
 
-        while self.log.last_idx != idx as usize {
-            let b = buf[self.log.last_idx];
-            if b == b'\r' || b == b'\n' {
-                if self.log.buf_count != 0 {
-                    let s = unsafe { core::str::from_utf8_unchecked(&self.log.buf[..self.log.buf_count]) };
-                    debug!("LOGS: {}", s);
-                    self.log.buf_count = 0;
-                }
-            } else if self.log.buf_count < self.log.buf.len() {
-                self.log.buf[self.log.buf_count] = b;
-                self.log.buf_count += 1;
+            // `bp_read` needs a 4-aligned address.
+            let aligned = start & !3;
+            let len = (end - aligned).min(buf.len());
+            self.bus.bp_read(log.buf + aligned as u32, &mut buf[..len]).await;
+
+            for &b in &buf[start - aligned..len] {
+                self.log_byte(b);
             }
 
-            self.log.last_idx += 1;
-            if self.log.last_idx == 0x400 {
+            self.log.last_idx = aligned + len;
+            if self.log.last_idx == size {
                 self.log.last_idx = 0;
             }
         }
     }
 
+    #[cfg(feature = "firmware-logs")]
+    fn log_byte(&mut self, b: u8) {
+        if b == b'\r' || b == b'\n' {
+            if self.log.buf_count != 0 {
+                self.log_line();
+            }
+        } else {
+            self.log.buf[self.log.buf_count] = b;
+            self.log.buf_count += 1;
+
+            // Split overlong lines instead of truncating them.
+            if self.log.buf_count == self.log.buf.len() {
+                self.log_line();
+            }
+        }
+    }
+
+    #[cfg(feature = "firmware-logs")]
+    fn log_line(&mut self) {
+        let line = &self.log.buf[..self.log.buf_count];
+        debug!("LOGS: {}", utf8_prefix(line));
+        if let Some(sink) = self.log.sink {
+            sink.push_line(line);
+        }
+        self.log.buf_count = 0;
+    }
+
+    /// Set the function deciding the 802.1D priority of transmitted frames, and with it their WMM
+    /// access category. Defaults to [`classify_dscp`].
+    pub fn set_tx_classifier(&mut self, classifier: TxClassifier) {
+        self.classify = classifier;
+    }
+
+    /// Forward the firmware console to a [`FirmwareLog`] reader, buffering complete lines in `buffer`.
+    ///
+    /// This must be called before the runner is started with [`run`](Self::run).
+    #[cfg(feature = "firmware-logs")]
+    pub fn firmware_log<const N: usize>(&mut self, buffer: &'a FirmwareLogBuffer<N>) -> FirmwareLog<'a> {
+        buffer.set_interval(self.log.interval);
+        self.log.sink = Some(buffer);
+        FirmwareLog::new(buffer)
+    }
+
+    /// Set how often the firmware console is polled. Defaults to 100ms.
+    #[cfg(feature = "firmware-logs")]
+    pub fn set_firmware_log_interval(&mut self, interval: Duration) {
+        self.log.interval = interval;
+        if let Some(sink) = self.log.sink {
+            sink.set_interval(interval);
+        }
+    }
+
+    /// When the next periodic task (firmware log read, crash check) is due.
+    fn poll_at(&self) -> Instant {
+        let at = self.crash_check_at;
+        #[cfg(feature = "firmware-logs")]
+        let at = at.min(self.log.read_at);
+        at
+    }
+
+    /// Run the periodic tasks that are due.
+    async fn poll(&mut self, buf: &mut [u32]) {
+        let now = Instant::now();
+
+        #[cfg(feature = "firmware-logs")]
+        if now >= self.log.read_at {
+            self.log_read(buf).await;
+        }
+        #[cfg(not(feature = "firmware-logs"))]
+        let _ = buf;
+
+        if now >= self.crash_check_at {
+            self.check_crash().await;
+        }
+    }
+
     pub async fn run(mut self) -> ! {
-        let mut buf = [0; 512];
+        let buf = core::mem::take(&mut self.buf);
         loop {
-            #[cfg(feature = "firmware-logs")]
-            self.log_read().await;
+            let poll_at = self.poll_at();
 
             if self.has_credit() {
+                // Hold back data frames while the firmware flow-controls them, IOCTLs still go through.
+                let tx_blocked = self
+                    .ch
+                    .try_tx_buf()
+                    .map_or(false, |packet| !self.flow.can_send((self.classify)(packet)));
+
                 let ioctl = self.ioctl_state.wait_pending();
-                let tx = self.ch.tx_buf();
-                let ev = self.bus.wait_for_event();
+                let ch = &mut self.ch;
+                let tx = async move {
+                    if tx_blocked {
+                        pending().await
+                    } else {
+                        ch.tx_buf().await
+                    }
+                };
+                let ev = select(self.bus.wait_for_event(), bt_wait(&self.bt));
+                let poll = Timer::at(poll_at);
 
-                match select3(ioctl, tx, ev).await {
-                    Either3::First(PendingIoctl {
+                match select4(ioctl, tx, ev, poll).await {
+                    Either4::First(PendingIoctl {
                         buf: iobuf,
                         kind,
                         cmd,
                         iface,
                     }) => {
-                        self.send_ioctl(kind, cmd, iface, unsafe { &*iobuf }).await;
-                        self.check_status(&mut buf).await;
+                        self.send_ioctl(kind, cmd, iface, unsafe { &*iobuf }, buf).await;
+                        self.check_status(buf).await;
                     }
-                    Either3::Second(packet) => {
+                    Either4::Second(packet) => {
+                        let priority = (self.classify)(packet) & 0x07;
+                        if !self.flow.can_send(priority) {
+                            continue;
+                        }
+
                         trace!("tx pkt {:02x}", Bytes(&packet[..packet.len().min(48)]));
 
-                        let mut buf = [0; 512];
-                        let buf8 = slice8_mut(&mut buf);
+                        let mut header = [0; (SdpcmHeader::SIZE + BcdHeader::SIZE) / 4];
+                        let header8 = slice8_mut(&mut header);
 
                         let total_len = SdpcmHeader::SIZE + BcdHeader::SIZE + packet.len();
 
@@ -270,92 +697,255 @@ where
 
                         let bcd_header = BcdHeader {
                             flags: BDC_VERSION << BDC_VERSION_SHIFT,
-                            priority: 0,
+                            priority,
                             flags2: 0,
                             data_offset: 0,
                         };
                         trace!("tx {:?}", sdpcm_header);
                         trace!("    {:?}", bcd_header);
 
-                        buf8[0..SdpcmHeader::SIZE].copy_from_slice(&sdpcm_header.to_bytes());
-                        buf8[SdpcmHeader::SIZE..][..BcdHeader::SIZE].copy_from_slice(&bcd_header.to_bytes());
-                        buf8[SdpcmHeader::SIZE + BcdHeader::SIZE..][..packet.len()].copy_from_slice(packet);
-
-                        let total_len = (total_len + 3) & !3; // round up to 4byte
-
-                        trace!("    {:02x}", Bytes(&buf8[..total_len.min(48)]));
+                        header8[0..SdpcmHeader::SIZE].copy_from_slice(&sdpcm_header.to_bytes());
+                        header8[SdpcmHeader::SIZE..][..BcdHeader::SIZE].copy_from_slice(&bcd_header.to_bytes());
 
-                        self.bus.wlan_write(&buf[..(total_len / 4)]).await;
+                        self.bus.wlan_write(&header, packet, buf).await;
+                        self.last_tx_ioctl = false;
                         self.ch.tx_done();
-                        self.check_status(&mut buf).await;
+                        self.flow.sent(priority);
+                        self.check_status(buf).await;
                     }
-                    Either3::Third(()) => {
-                        self.handle_irq(&mut buf).await;
+                    Either4::Third(Either::First(())) => {
+                        self.handle_irq(buf).await;
+                    }
+                    Either4::Third(Either::Second(())) => {
+                        self.bt_poll().await;
+                    }
+                    Either4::Fourth(()) => {
+                        self.poll(buf).await;
                     }
                 }
             } else {
                 warn!("TX stalled");
-                self.bus.wait_for_event().await;
-                self.handle_irq(&mut buf).await;
+                let ev = select(self.bus.wait_for_event(), bt_wait(&self.bt));
+                let poll = Timer::at(poll_at);
+
+                match select(ev, poll).await {
+                    Either::First(Either::First(())) => self.handle_irq(buf).await,
+                    Either::First(Either::Second(())) => self.bt_poll().await,
+                    Either::Second(()) => self.poll(buf).await,
+                }
             }
         }
     }
 
     /// Wait for IRQ on F2 packet available
-    async fn handle_irq(&mut self, buf: &mut [u32; 512]) {
+    async fn handle_irq(&mut self, buf: &mut [u32]) {
         // Receive stuff
-        let irq = self.bus.read16(FUNC_BUS, REG_BUS_INTERRUPT).await;
+        let irq = self.bus.interrupts(self.chip).await;
         trace!("irq{}", FormatInterrupt(irq));
 
+        if irq & IRQ_BUS_ERRORS != 0 {
+            self.handle_bus_errors(irq).await;
+        }
+
         if irq & IRQ_F2_PACKET_AVAILABLE != 0 {
             self.check_status(buf).await;
         }
 
-        if irq & IRQ_DATA_UNAVAILABLE != 0 {
-            // TODO what should we do here?
-            warn!("IRQ DATA_UNAVAILABLE, clearing...");
-            self.bus.write16(FUNC_BUS, REG_BUS_INTERRUPT, 1).await;
+        if irq & IRQ_F1_INTR != 0 {
+            if let Some(bt) = &mut self.bt {
+                bt.handle_irq(&mut self.bus, self.chip).await;
+            }
         }
     }
 
-    /// Handle F2 events while status register is set
-    async fn check_status(&mut self, buf: &mut [u32; 512]) {
-        loop {
-            let status = self.bus.status();
-            trace!("check status{}", FormatStatus(status));
-
-            if status & STATUS_F2_PKT_AVAILABLE != 0 {
-                let len = (status & STATUS_F2_PKT_LEN_MASK) >> STATUS_F2_PKT_LEN_SHIFT;
-                self.bus.wlan_read(buf, len).await;
-                trace!("rx {:02x}", Bytes(&slice8_mut(buf)[..(len as usize).min(48)]));
-                self.rx(&slice8_mut(buf)[..len as usize]);
-            } else {
-                break;
+    /// Move HCI packets between the [`BtDriver`](crate::BtDriver) and the controller.
+    async fn bt_poll(&mut self) {
+        if let Some(bt) = &mut self.bt {
+            bt.poll(&mut self.bus).await;
+        }
+    }
+
+    /// Clear and count bus error interrupts, and get the F2 receive path back into a known state.
+    async fn handle_bus_errors(&mut self, irq: u16) {
+        warn!("bus error, irq{}", FormatInterrupt(irq & IRQ_BUS_ERRORS));
+        self.bus.clear_interrupts(self.chip, irq & IRQ_BUS_ERRORS).await;
+
+        let mut errors = self.bus_errors.get();
+        let count = |bit: u16, counter: &mut u32| {
+            if irq & bit != 0 {
+                *counter = counter.wrapping_add(1);
             }
+        };
+        count(IRQ_DATA_UNAVAILABLE, &mut errors.data_unavailable);
+        count(IRQ_F2_F3_FIFO_RD_UNDERFLOW, &mut errors.fifo_underflow);
+        count(IRQ_F2_F3_FIFO_WR_OVERFLOW, &mut errors.fifo_overflow);
+        count(IRQ_COMMAND_ERROR, &mut errors.command_error);
+        count(IRQ_DATA_ERROR, &mut errors.data_error);
+        count(IRQ_F1_OVERFLOW, &mut errors.f1_overflow);
+        self.bus_errors.set(errors);
+
+        // A TX frame hit by a write overflow is lost. A data packet was already handed back to the
+        // network stack so there is nothing to retry, but an ioctl would never get a response. The
+        // firmware grants credit again in the header of the next frame it sends.
+        if irq & IRQ_F2_F3_FIFO_WR_OVERFLOW != 0 && self.last_tx_ioctl {
+            self.ioctl_state.ioctl_failed(IoctlError::Lost);
+        }
+
+        if irq & (IRQ_DATA_UNAVAILABLE | IRQ_F2_F3_FIFO_RD_UNDERFLOW | IRQ_COMMAND_ERROR | IRQ_DATA_ERROR) != 0 {
+            // The last read may have been cut short or garbled. Drop whatever is left of the
+            // frame, along with any superframe announced before it, and take the sequence number
+            // of the next frame as is.
+            self.bus.wlan_drop().await;
+            self.glom = None;
+            self.rx_seq = None;
         }
     }
 
-    fn rx(&mut self, packet: &[u8]) {
-        if packet.len() < SdpcmHeader::SIZE {
-            warn!("packet too short, len={}", packet.len());
-            return;
+    /// Check the sequence number of a received frame against the expected one.
+    fn check_rx_seq(&mut self, seq: u8) {
+        if let Some(expected) = self.rx_seq {
+            if seq != expected {
+                warn!("rx seq mismatch, got {} expected {}", seq, expected);
+                let mut errors = self.bus_errors.get();
+                errors.rx_seq_mismatch = errors.rx_seq_mismatch.wrapping_add(1);
+                self.bus_errors.set(errors);
+            }
         }
+        self.rx_seq = Some(seq.wrapping_add(1));
+    }
+
+    /// Handle F2 frames while the chip has any
+    async fn check_status(&mut self, buf: &mut [u32]) {
+        while let Some(len) = self.bus.wlan_pending().await {
+            if len as usize > buf.len() * 4 {
+                warn!("rx packet too big for transfer buffer, len={}, dropping", len);
+                self.bus.wlan_drop().await;
+                continue;
+            }
+            self.bus.wlan_read(buf, len).await;
+            trace!("rx {:02x}", Bytes(&slice8_mut(buf)[..(len as usize).min(48)]));
+            self.rx(&slice8_mut(buf)[..len as usize]);
+        }
+    }
 
-        let sdpcm_header = SdpcmHeader::from_bytes(packet[..SdpcmHeader::SIZE].try_into().unwrap());
-        trace!("rx {:?}", sdpcm_header);
-        if sdpcm_header.len != !sdpcm_header.len_inv {
-            warn!("len inv mismatch");
+    /// Handle one F2 read. This is either a single SDPCM frame, a glom descriptor, or the
+    /// superframe announced by the previous glom descriptor.
+    fn rx(&mut self, packet: &[u8]) {
+        let Some(sdpcm_header) = parse_sdpcm_header(packet) else {
             return;
+        };
+        let glom_channel = sdpcm_header.channel_and_flags & SDPCM_CHANNEL_MASK == CHANNEL_TYPE_GLOM;
+        let glom_desc = sdpcm_header.channel_and_flags & SDPCM_GLOMDESC_FLAG != 0;
+
+        if let Some(glom) = self.glom.take() {
+            if glom_channel && !glom_desc {
+                self.update_credit(&sdpcm_header);
+                self.rx_superframe(&sdpcm_header, packet, &glom);
+                // Subframe sequence numbers aren't tracked, pick up from the next frame.
+                self.rx_seq = None;
+                return;
+            }
+            warn!("glom descriptor not followed by its superframe, dropping it");
         }
+
         if sdpcm_header.len as usize != packet.len() {
             // TODO: is this guaranteed??
             warn!("len from header doesn't match len from spi");
             return;
         }
-
+        self.check_rx_seq(sdpcm_header.sequence);
         self.update_credit(&sdpcm_header);
 
-        let channel = sdpcm_header.channel_and_flags & 0x0f;
+        if glom_channel {
+            if glom_desc {
+                self.rx_glom_desc(&sdpcm_header, packet);
+            } else {
+                warn!("superframe without glom descriptor, dropping");
+            }
+            return;
+        }
+
+        self.rx_frame(&sdpcm_header, packet);
+    }
+
+    /// Parse a glom descriptor. Its payload is a list of little-endian u16 subframe lengths,
+    /// the superframe itself follows in the next F2 read.
+    fn rx_glom_desc(&mut self, sdpcm_header: &SdpcmHeader, packet: &[u8]) {
+        let payload = &packet[sdpcm_header.header_length as usize..];
+
+        let mut glom = GlomDesc {
+            lens: [0; SDPCM_MAX_GLOM],
+            count: 0,
+        };
+        for len in payload.chunks_exact(2) {
+            if glom.count == SDPCM_MAX_GLOM {
+                warn!("glom descriptor has too many subframes, dropping");
+                return;
+            }
+            glom.lens[glom.count] = u16::from_le_bytes([len[0], len[1]]);
+            glom.count += 1;
+        }
+
+        if glom.count == 0 {
+            warn!("empty glom descriptor");
+            return;
+        }
+
+        trace!("glom desc {:?}", &glom.lens[..glom.count]);
+        self.glom = Some(glom);
+    }
+
+    /// Split a superframe into its subframes and handle each of them.
+    ///
+    /// The superframe starts with its own SDPCM header on the glom channel, whose `len` covers the
+    /// whole superframe. The first subframe follows that header, and every subframe has a regular
+    /// SDPCM header. Subframe lengths from the descriptor include padding, the first one also
+    /// includes the superframe header.
+    fn rx_superframe(&mut self, super_header: &SdpcmHeader, packet: &[u8], glom: &GlomDesc) {
+        let total_len: usize = glom.lens[..glom.count].iter().map(|&l| l as usize).sum();
+        if total_len > packet.len() {
+            warn!("superframe too short, len={} expected={}", packet.len(), total_len);
+            return;
+        }
+        if super_header.len as usize != total_len {
+            warn!(
+                "superframe len mismatch, header={} descriptor={}",
+                super_header.len, total_len
+            );
+            return;
+        }
+
+        let mut start = super_header.header_length as usize;
+        let mut end = 0;
+        for &len in &glom.lens[..glom.count] {
+            end += len as usize;
+            if start >= end {
+                warn!("subframe out of range");
+                return;
+            }
+            let subframe = &packet[start..end];
+            start = end;
+
+            let Some(sdpcm_header) = parse_sdpcm_header(subframe) else {
+                continue;
+            };
+            if sdpcm_header.len as usize > subframe.len() {
+                warn!(
+                    "subframe len from header {} exceeds len from descriptor {}",
+                    sdpcm_header.len,
+                    subframe.len()
+                );
+                continue;
+            }
+
+            self.rx_frame(&sdpcm_header, &subframe[..sdpcm_header.len as usize]);
+        }
+    }
+
+    /// Handle a single, already validated SDPCM frame. Credit is updated by the caller, from the
+    /// header of the whole F2 read.
+    fn rx_frame(&mut self, sdpcm_header: &SdpcmHeader, packet: &[u8]) {
+        let channel = sdpcm_header.channel_and_flags & SDPCM_CHANNEL_MASK;
 
         let payload = &packet[sdpcm_header.header_length as _..];
 
@@ -371,8 +961,10 @@ where
 
                 if cdc_header.id == self.ioctl_id {
                     if cdc_header.status != 0 {
-                        // TODO: propagate error instead
-                        panic!("IOCTL error {}", cdc_header.status as i32);
+                        warn!("IOCTL error {}", cdc_header.status as i32);
+                        self.ioctl_state
+                            .ioctl_failed(IoctlError::Firmware(cdc_header.status as i32));
+                        return;
                     }
 
                     let resp_len = cdc_header.len as usize;
@@ -392,6 +984,8 @@ where
                     warn!("BCD event, incomplete header");
                     return;
                 }
+                self.flow.signals(&payload[BcdHeader::SIZE..packet_start]);
+
                 let bcd_packet = &payload[packet_start..];
                 trace!("    {:02x}", Bytes(&bcd_packet[..(bcd_packet.len() as usize).min(36)]));
 
@@ -441,6 +1035,10 @@ where
                     Bytes(evt_data)
                 );
 
+                if evt_type == events::Event::FIFO_CREDIT_MAP {
+                    self.flow.credit_map(evt_data);
+                }
+
                 if evt_type == events::Event::AUTH || evt_type == events::Event::JOIN {
                     self.events.publish_immediate(EventStatus {
                         status: event_packet.msg.status,
@@ -457,9 +1055,16 @@ where
                     warn!("packet start out of range.");
                     return;
                 }
+                self.flow.signals(&payload[BcdHeader::SIZE..packet_start]);
+
                 let packet = &payload[packet_start..];
                 trace!("rx pkt {:02x}", Bytes(&packet[..(packet.len() as usize).min(48)]));
 
+                if packet.len() > MTU {
+                    warn!("rxd packet larger than MTU, len={}, dropping", packet.len());
+                    return;
+                }
+
                 match self.ch.try_rx_buf() {
                     Some(buf) => {
                         buf[..packet.len()].copy_from_slice(packet);
@@ -473,12 +1078,14 @@ where
     }
 
     fn update_credit(&mut self, sdpcm_header: &SdpcmHeader) {
-        if sdpcm_header.channel_and_flags & 0xf < 3 {
+        if sdpcm_header.channel_and_flags & SDPCM_CHANNEL_MASK <= CHANNEL_TYPE_GLOM {
             let mut sdpcm_seq_max = sdpcm_header.bus_data_credit;
             if sdpcm_seq_max.wrapping_sub(self.sdpcm_seq) > 0x40 {
                 sdpcm_seq_max = self.sdpcm_seq + 2;
             }
             self.sdpcm_seq_max = sdpcm_seq_max;
+
+            self.flow.update_bitmap(sdpcm_header.wireless_flow_control);
         }
     }
 
@@ -486,9 +1093,9 @@ where
         self.sdpcm_seq != self.sdpcm_seq_max && self.sdpcm_seq_max.wrapping_sub(self.sdpcm_seq) & 0x80 == 0
     }
 
-    async fn send_ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, data: &[u8]) {
-        let mut buf = [0; 512];
-        let buf8 = slice8_mut(&mut buf);
+    async fn send_ioctl(&mut self, kind: IoctlType, cmd: u32, iface: u32, data: &[u8], scratch: &mut [u32]) {
+        let mut header = [0; (SdpcmHeader::SIZE + CdcHeader::SIZE) / 4];
+        let header8 = slice8_mut(&mut header);
 
         let total_len = SdpcmHeader::SIZE + CdcHeader::SIZE + data.len();
 
@@ -518,19 +1125,17 @@ where
         trace!("tx {:?}", sdpcm_header);
         trace!("    {:?}", cdc_header);
 
-        buf8[0..SdpcmHeader::SIZE].copy_from_slice(&sdpcm_header.to_bytes());
-        buf8[SdpcmHeader::SIZE..][..CdcHeader::SIZE].copy_from_slice(&cdc_header.to_bytes());
-        buf8[SdpcmHeader::SIZE + CdcHeader::SIZE..][..data.len()].copy_from_slice(data);
-
-        let total_len = (total_len + 3) & !3; // round up to 4byte
+        header8[0..SdpcmHeader::SIZE].copy_from_slice(&sdpcm_header.to_bytes());
+        header8[SdpcmHeader::SIZE..][..CdcHeader::SIZE].copy_from_slice(&cdc_header.to_bytes());
 
-        trace!("    {:02x}", Bytes(&buf8[..total_len.min(48)]));
+        trace!("    {:02x}", Bytes(&data[..data.len().min(48)]));
 
-        self.bus.wlan_write(&buf[..total_len / 4]).await;
+        self.bus.wlan_write(&header, data, scratch).await;
+        self.last_tx_ioctl = true;
     }
 
     async fn core_disable(&mut self, core: Core) {
-        let base = core.base_addr();
+        let base = core.base_addr(self.chip);
 
         // Dummy read?
         let _ = self.bus.bp_read8(base + AI_RESETCTRL_OFFSET).await;
@@ -555,7 +1160,7 @@ where
     async fn core_reset(&mut self, core: Core) {
         self.core_disable(core).await;
 
-        let base = core.base_addr();
+        let base = core.base_addr(self.chip);
         self.bus
             .bp_write8(base + AI_IOCTRL_OFFSET, AI_IOCTRL_BIT_FGC | AI_IOCTRL_BIT_CLOCK_EN)
             .await;
@@ -574,7 +1179,7 @@ where
     }
 
     async fn core_is_up(&mut self, core: Core) -> bool {
-        let base = core.base_addr();
+        let base = core.base_addr(self.chip);
 
         let io = self.bus.bp_read8(base + AI_IOCTRL_OFFSET).await;
         if io & (AI_IOCTRL_BIT_FGC | AI_IOCTRL_BIT_CLOCK_EN) != AI_IOCTRL_BIT_CLOCK_EN {
@@ -592,6 +1197,54 @@ where
     }
 }
 
+/// Wait until the Bluetooth runner has work, never if Bluetooth is disabled.
+async fn bt_wait(bt: &Option<BtRunner<'_>>) {
+    match bt {
+        Some(bt) => bt.wait().await,
+        None => pending().await,
+    }
+}
+
+/// Check that `firmware`, or at least its last kilobyte, is for `chip`.
+fn check_firmware(chip: &Chip, firmware: &[u8]) -> Result<(), InitError> {
+    match chip.matches_firmware(firmware) {
+        Some(true) => Ok(()),
+        Some(false) => Err(InitError::FirmwareMismatch),
+        None => {
+            warn!("no version string in firmware, can't check it is for chip {}", chip.id);
+            Ok(())
+        }
+    }
+}
+
+/// First word of a firmware image, 0 if it is shorter.
+fn first_word(firmware: &[u8]) -> u32 {
+    firmware
+        .get(..4)
+        .map_or(0, |w| u32::from_le_bytes(w.try_into().unwrap()))
+}
+
+fn parse_sdpcm_header(packet: &[u8]) -> Option<SdpcmHeader> {
+    if packet.len() < SdpcmHeader::SIZE {
+        warn!("packet too short, len={}", packet.len());
+        return None;
+    }
+
+    let sdpcm_header = SdpcmHeader::from_bytes(packet[..SdpcmHeader::SIZE].try_into().unwrap());
+    trace!("rx {:?}", sdpcm_header);
+    if sdpcm_header.len != !sdpcm_header.len_inv {
+        warn!("len inv mismatch");
+        return None;
+    }
+    let header_length = sdpcm_header.header_length as usize;
+    if header_length < SdpcmHeader::SIZE || header_length > packet.len() {
+        warn!("header length out of range, len={}", sdpcm_header.header_length);
+        return None;
+    }
+
+    Some(sdpcm_header)
+}
+
 fn slice8_mut(x: &mut [u32]) -> &mut [u8] {
     let len = x.len() * 4;
     unsafe { slice::from_raw_parts_mut(x.as_mut_ptr() as _, len) }
@@ -0,0 +1,377 @@
+use core::slice;
+
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Instant};
+use embedded_hal_1::digital::OutputPin;
+
+use crate::bus::{sealed, Bus, BusHost, Transport};
+use crate::chip::Chip;
+use crate::consts::*;
+use crate::InitError;
+
+/// How long to wait for the card to respond and for function 1 to become ready after power up.
+const SDIO_READY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many times to poll the read frame byte count for a frame terminate to complete.
+const RF_TERM_RETRIES: usize = 0xff;
+
+/// SDIO host controller trait that _only_ supports the bus operations of the cyw43.
+pub trait SdioBusCyw43 {
+    /// Initialize the card after it was powered on: CMD0, CMD5 until the card is ready, CMD3 and
+    /// CMD7 to select it. The bus width and clock are switched to the fastest mode both sides
+    /// support, usually 4-bit. Returns `false` if the card did not respond.
+    async fn init(&mut self) -> bool;
+
+    /// Issues a CMD52 (IO_RW_DIRECT) with argument `arg`, and returns the data byte of the response.
+    async fn cmd52(&mut self, arg: u32) -> u8;
+
+    /// Issues a CMD53 (IO_RW_EXTENDED) read with argument `arg`.
+    /// `read` is at least as long as the transfer, rounded up to a word.
+    async fn cmd53_read(&mut self, arg: u32, read: &mut [u32]);
+
+    /// Issues a CMD53 (IO_RW_EXTENDED) write with argument `arg`.
+    /// The data is the words of every segment in `write`, in order. Segments may be empty.
+    async fn cmd53_write(&mut self, arg: u32, write: &[&[u32]]);
+
+    /// Wait for events from the Device. A typical implementation would wait for the SDIO card
+    /// interrupt on DAT1, or for the out-of-band interrupt pin.
+    /// The default implementation always reports ready, resulting in active polling of the device.
+    async fn wait_for_event(&mut self) {
+        yield_now().await;
+    }
+}
+
+/// SDIO transport, used in place of a [`SpiBusCyw43`](crate::SpiBusCyw43) for chips connected over SDIO.
+pub struct Sdio<H> {
+    host: H,
+    f2_enabled: bool,
+    /// The chip indicated frames that haven't been read yet.
+    rx_pending: bool,
+    /// Tag of the frame announced by `wlan_pending`, already read from the FIFO.
+    rx_tag: Option<u32>,
+}
+
+impl<H: SdioBusCyw43> Sdio<H> {
+    pub fn new(host: H) -> Self {
+        Self {
+            host,
+            f2_enabled: false,
+            rx_pending: false,
+            rx_tag: None,
+        }
+    }
+
+    async fn cmd52_read(&mut self, func: u32, addr: u32) -> u8 {
+        self.host.cmd52(cmd52_arg(READ, func, addr, 0)).await
+    }
+
+    async fn cmd52_write(&mut self, func: u32, addr: u32, val: u8) {
+        self.host.cmd52(cmd52_arg(WRITE, func, addr, val)).await;
+    }
+
+    /// Read `len` bytes. Function 2 is a FIFO, other functions are read from incrementing addresses.
+    async fn cmd53_read(&mut self, func: u32, addr: u32, buf: &mut [u32], len: usize) {
+        let incr = func != FUNC_WLAN;
+
+        let blocks = len / SDIO_BLOCK_SIZE;
+        if blocks != 0 {
+            let arg = cmd53_arg(READ, func, true, incr, addr, blocks as u32);
+            self.host
+                .cmd53_read(arg, &mut buf[..blocks * SDIO_BLOCK_SIZE / 4])
+                .await;
+        }
+
+        let rest = len - blocks * SDIO_BLOCK_SIZE;
+        if rest != 0 {
+            let addr = if incr {
+                addr + (blocks * SDIO_BLOCK_SIZE) as u32
+            } else {
+                addr
+            };
+            let arg = cmd53_arg(READ, func, false, incr, addr, rest as u32);
+            let buf = &mut buf[blocks * SDIO_BLOCK_SIZE / 4..];
+            self.host.cmd53_read(arg, &mut buf[..(rest + 3) / 4]).await;
+        }
+    }
+}
+
+impl<H: SdioBusCyw43> BusHost for Sdio<H> {}
+
+impl<H: SdioBusCyw43> sealed::BusHost for Sdio<H> {
+    type Transport = Self;
+
+    fn into_transport(self) -> Self {
+        self
+    }
+}
+
+impl<H: SdioBusCyw43> Transport for Sdio<H> {
+    async fn init(&mut self) -> Result<(), InitError> {
+        self.f2_enabled = false;
+        self.rx_pending = false;
+        self.rx_tag = None;
+
+        if !self.host.init().await {
+            return Err(InitError::BusNotReady);
+        }
+
+        // Set the block size, retrying until it reads back. This doubles as the bus test.
+        let deadline = Instant::now() + SDIO_READY_TIMEOUT;
+        loop {
+            self.cmd52_write(FUNC_BUS, SDIO_FBR_F1_BLOCK_SIZE, SDIO_BLOCK_SIZE as u8)
+                .await;
+            if self.cmd52_read(FUNC_BUS, SDIO_FBR_F1_BLOCK_SIZE).await == SDIO_BLOCK_SIZE as u8 {
+                break;
+            }
+            if Instant::now() > deadline {
+                return Err(InitError::BusTest);
+            }
+        }
+        self.cmd52_write(FUNC_BUS, SDIO_FBR_F1_BLOCK_SIZE + 1, 0).await;
+        self.cmd52_write(FUNC_BUS, SDIO_FBR_F2_BLOCK_SIZE, SDIO_BLOCK_SIZE as u8)
+            .await;
+        self.cmd52_write(FUNC_BUS, SDIO_FBR_F2_BLOCK_SIZE + 1, 0).await;
+
+        // Enable the backplane function.
+        self.cmd52_write(FUNC_BUS, SDIO_CCCR_IOEN, SDIO_FUNC_1).await;
+        let deadline = Instant::now() + SDIO_READY_TIMEOUT;
+        while self.cmd52_read(FUNC_BUS, SDIO_CCCR_IORDY).await & SDIO_FUNC_1 == 0 {
+            if Instant::now() > deadline {
+                return Err(InitError::BusNotReady);
+            }
+        }
+
+        self.cmd52_write(FUNC_BUS, SDIO_CCCR_INTEN, SDIO_INT_MASTER | SDIO_FUNC_1 | SDIO_FUNC_2)
+            .await;
+
+        Ok(())
+    }
+
+    async fn read_reg(&mut self, func: u32, addr: u32, len: u32) -> u32 {
+        if len == 1 {
+            return self.cmd52_read(func, addr).await as u32;
+        }
+
+        let mut buf = [0; 1];
+        let arg = cmd53_arg(READ, func, false, true, addr, len);
+        self.host.cmd53_read(arg, &mut buf).await;
+        buf[0] & (u32::MAX >> (32 - len * 8))
+    }
+
+    async fn write_reg(&mut self, func: u32, addr: u32, val: u32, len: u32) {
+        if len == 1 {
+            return self.cmd52_write(func, addr, val as u8).await;
+        }
+
+        let arg = cmd53_arg(WRITE, func, false, true, addr, len);
+        self.host.cmd53_write(arg, &[&[val]]).await;
+    }
+
+    async fn bp_read(&mut self, addr: u32, data: &mut [u8]) {
+        let mut buf = [0u32; BACKPLANE_MAX_TRANSFER_SIZE / 4];
+        let len = data.len();
+
+        let arg = cmd53_arg(
+            READ,
+            FUNC_BACKPLANE,
+            false,
+            true,
+            addr | BACKPLANE_ADDRESS_32BIT_FLAG,
+            len as u32,
+        );
+        self.host.cmd53_read(arg, &mut buf[..(len + 3) / 4]).await;
+
+        data.copy_from_slice(&slice8_mut(&mut buf)[..len]);
+    }
+
+    async fn bp_write(&mut self, addr: u32, data: &[u8]) {
+        let mut buf = [0u32; BACKPLANE_MAX_TRANSFER_SIZE / 4];
+        let len = data.len();
+        slice8_mut(&mut buf)[..len].copy_from_slice(data);
+
+        let arg = cmd53_arg(
+            WRITE,
+            FUNC_BACKPLANE,
+            false,
+            true,
+            addr | BACKPLANE_ADDRESS_32BIT_FLAG,
+            len as u32,
+        );
+        self.host.cmd53_write(arg, &[&buf[..(len + 3) / 4]]).await;
+    }
+
+    async fn interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip) -> u16 {
+        let addr = chip.sdiod_core_base_address + SDIOD_INT_STATUS;
+        let status = bus.bp_read32(addr).await;
+
+        // Acknowledge right away, the card interrupt stays asserted until then.
+        if status != 0 {
+            bus.bp_write32(addr, status).await;
+        }
+        if status & SDIOD_I_HMB_FRAME_IND != 0 {
+            bus.transport().rx_pending = true;
+        }
+
+        irq_from_sdiod(status)
+    }
+
+    async fn clear_interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip, irq: u16) {
+        let addr = chip.sdiod_core_base_address + SDIOD_INT_STATUS;
+        bus.bp_write32(addr, irq_to_sdiod(irq)).await;
+    }
+
+    async fn enable_interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip, irq: u16) {
+        let addr = chip.sdiod_core_base_address + SDIOD_HOST_INT_MASK;
+        bus.bp_write32(addr, irq_to_sdiod(irq)).await;
+    }
+
+    async fn wlan_ready(&mut self) -> bool {
+        if !self.f2_enabled {
+            self.cmd52_write(FUNC_BUS, SDIO_CCCR_IOEN, SDIO_FUNC_1 | SDIO_FUNC_2)
+                .await;
+            self.f2_enabled = true;
+        }
+        self.cmd52_read(FUNC_BUS, SDIO_CCCR_IORDY).await & SDIO_FUNC_2 != 0
+    }
+
+    async fn wlan_pending(&mut self) -> Option<u32> {
+        if let Some(tag) = self.rx_tag {
+            return Some(tag & 0xffff);
+        }
+        if !self.rx_pending {
+            return None;
+        }
+
+        // There's no length register, every frame starts with its length and the inverted length.
+        // Frames are read until the chip returns an empty tag.
+        let mut tag = [0; 1];
+        self.cmd53_read(FUNC_WLAN, 0, &mut tag, 4).await;
+        let tag = tag[0];
+
+        let len = tag & 0xffff;
+        if len == 0 || (tag >> 16) ^ len != 0xffff {
+            if len != 0 {
+                warn!("bad frame tag {:08x}, dropping", tag);
+                self.wlan_drop().await;
+            }
+            self.rx_pending = false;
+            return None;
+        }
+
+        self.rx_tag = Some(tag);
+        Some(len)
+    }
+
+    async fn wlan_read(&mut self, buf: &mut [u32], len_in_u8: u32) {
+        let Some(tag) = self.rx_tag.take() else {
+            return;
+        };
+
+        // The tag has already been read.
+        buf[0] = tag;
+        let len = (len_in_u8 as usize).saturating_sub(4);
+        self.cmd53_read(FUNC_WLAN, 0, &mut buf[1..], len).await;
+    }
+
+    async fn wlan_drop(&mut self) {
+        self.rx_tag = None;
+        self.cmd52_write(FUNC_BACKPLANE, REG_BACKPLANE_FRAME_CONTROL, FRAME_CONTROL_RF_TERM)
+            .await;
+
+        // Wait for the chip to discard the frame.
+        for _ in 0..RF_TERM_RETRIES {
+            let hi = self.cmd52_read(FUNC_BACKPLANE, REG_BACKPLANE_READ_FRAME_BC_HIGH).await;
+            let lo = self.cmd52_read(FUNC_BACKPLANE, REG_BACKPLANE_READ_FRAME_BC_LOW).await;
+            if hi == 0 && lo == 0 {
+                return;
+            }
+        }
+        warn!("frame terminate didn't complete");
+    }
+
+    async fn wlan_write(&mut self, data: &[&[u32]], len: u32) {
+        static PADDING: [u32; SDIO_BLOCK_SIZE / 4] = [0; SDIO_BLOCK_SIZE / 4];
+
+        let len = len as usize;
+        if len < SDIO_BLOCK_SIZE {
+            let arg = cmd53_arg(WRITE, FUNC_WLAN, false, false, 0, len as u32);
+            self.host.cmd53_write(arg, data).await;
+            return;
+        }
+
+        // Pad to whole blocks, the frame length is in the SDPCM header.
+        let blocks = (len + SDIO_BLOCK_SIZE - 1) / SDIO_BLOCK_SIZE;
+        let padding = (blocks * SDIO_BLOCK_SIZE - len) / 4;
+
+        let mut segments: [&[u32]; 4] = [&[]; 4];
+        segments[..data.len()].copy_from_slice(data);
+        segments[data.len()] = &PADDING[..padding];
+
+        let arg = cmd53_arg(WRITE, FUNC_WLAN, true, false, 0, blocks as u32);
+        self.host.cmd53_write(arg, &segments[..data.len() + 1]).await;
+    }
+
+    async fn wait_for_event(&mut self) {
+        self.host.wait_for_event().await;
+    }
+}
+
+fn cmd52_arg(write: bool, func: u32, addr: u32, val: u8) -> u32 {
+    (write as u32) << 31 | (func & 0b111) << 28 | (addr & 0x1FFFF) << 9 | val as u32
+}
+
+/// `count` is in bytes, or in blocks if `block` is set. The maximum count of 512 is encoded as 0.
+fn cmd53_arg(write: bool, func: u32, block: bool, incr: bool, addr: u32, count: u32) -> u32 {
+    (write as u32) << 31
+        | (func & 0b111) << 28
+        | (block as u32) << 27
+        | (incr as u32) << 26
+        | (addr & 0x1FFFF) << 9
+        | (count & 0x1FF)
+}
+
+/// Translate SDIOD core interrupt status bits to the gSPI `IRQ_*` bits the runner handles.
+fn irq_from_sdiod(status: u32) -> u16 {
+    let mut irq = 0;
+    if status & SDIOD_I_HMB_FRAME_IND != 0 {
+        irq |= IRQ_F2_PACKET_AVAILABLE;
+    }
+    if status & SDIOD_I_HMB_FC_CHANGE != 0 {
+        irq |= IRQ_F1_INTR;
+    }
+    if status & SDIOD_I_RD_OOSYNC != 0 {
+        irq |= IRQ_F2_F3_FIFO_RD_UNDERFLOW;
+    }
+    if status & SDIOD_I_WR_OOSYNC != 0 {
+        irq |= IRQ_F2_F3_FIFO_WR_OVERFLOW;
+    }
+    if status & SDIOD_I_ERRORS != 0 {
+        irq |= IRQ_DATA_ERROR;
+    }
+    irq
+}
+
+fn irq_to_sdiod(irq: u16) -> u32 {
+    let mut status = 0;
+    if irq & IRQ_F2_PACKET_AVAILABLE != 0 {
+        status |= SDIOD_I_HMB_FRAME_IND;
+    }
+    if irq & IRQ_F1_INTR != 0 {
+        status |= SDIOD_I_HMB_FC_CHANGE;
+    }
+    if irq & IRQ_F2_F3_FIFO_RD_UNDERFLOW != 0 {
+        status |= SDIOD_I_RD_OOSYNC;
+    }
+    if irq & IRQ_F2_F3_FIFO_WR_OVERFLOW != 0 {
+        status |= SDIOD_I_WR_OOSYNC;
+    }
+    if irq & IRQ_DATA_ERROR != 0 {
+        status |= SDIOD_I_ERRORS;
+    }
+    status
+}
+
+fn slice8_mut(x: &mut [u32]) -> &mut [u8] {
+    let len = x.len() * 4;
+    unsafe { slice::from_raw_parts_mut(x.as_mut_ptr() as _, len) }
+}
@@ -0,0 +1,247 @@
+use core::slice;
+
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Instant};
+use embedded_hal_1::digital::OutputPin;
+use futures::FutureExt;
+
+use crate::bus::{sealed, Bus, BusHost, Transport};
+use crate::chip::Chip;
+use crate::consts::*;
+use crate::InitError;
+
+/// How long to wait for the chip to answer on the bus after power up.
+const BUS_READY_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Custom Spi Trait that _only_ supports the bus operation of the cyw43
+/// Implementors are expected to hold the CS pin low during an operation.
+pub trait SpiBusCyw43 {
+    /// Issues a write command on the bus
+    /// `cmd` is the 32 bit cmd word. It is followed on the bus by the words of every segment in `write`, in order.
+    /// Segments may be empty.
+    async fn cmd_write(&mut self, cmd: u32, write: &[&[u32]]) -> u32;
+
+    /// Issues a read command on the bus
+    /// `write` is expected to be a 32 bit cmd word
+    /// `read` will contain the response of the device
+    /// Backplane reads have a response delay that produces one extra unspecified word at the beginning of `read`.
+    /// Callers that want to read `n` word from the backplane, have to provide a slice that is `n+1` words long.
+    async fn cmd_read(&mut self, write: u32, read: &mut [u32]) -> u32;
+
+    /// Wait for events from the Device. A typical implementation would wait for the IRQ pin to be high.
+    /// The default implementation always reports ready, resulting in active polling of the device.
+    async fn wait_for_event(&mut self) {
+        yield_now().await;
+    }
+}
+
+impl<T: SpiBusCyw43> BusHost for T {}
+
+impl<T: SpiBusCyw43> sealed::BusHost for T {
+    type Transport = Spi<T>;
+
+    fn into_transport(self) -> Spi<T> {
+        Spi { spi: self, status: 0 }
+    }
+}
+
+/// gSPI transport.
+pub struct Spi<SPI> {
+    spi: SPI,
+    /// Status word returned with the last transaction.
+    status: u32,
+}
+
+impl<SPI: SpiBusCyw43> Spi<SPI> {
+    async fn readn(&mut self, func: u32, addr: u32, len: u32) -> u32 {
+        let cmd = cmd_word(READ, INC_ADDR, func, addr, len);
+        let mut buf = [0; 2];
+        // if we are reading from the backplane, we need an extra word for the response delay
+        let len = if func == FUNC_BACKPLANE { 2 } else { 1 };
+
+        self.status = self.spi.cmd_read(cmd, &mut buf[..len]).await;
+
+        // if we read from the backplane, the result is in the second word, after the response delay
+        if func == FUNC_BACKPLANE {
+            buf[1]
+        } else {
+            buf[0]
+        }
+    }
+
+    async fn writen(&mut self, func: u32, addr: u32, val: u32, len: u32) {
+        let cmd = cmd_word(WRITE, INC_ADDR, func, addr, len);
+
+        self.status = self.spi.cmd_write(cmd, &[&[val]]).await;
+    }
+
+    async fn read32_swapped(&mut self, addr: u32) -> u32 {
+        let cmd = cmd_word(READ, INC_ADDR, FUNC_BUS, addr, 4);
+        let cmd = swap16(cmd);
+        let mut buf = [0; 1];
+
+        self.status = self.spi.cmd_read(cmd, &mut buf).await;
+
+        swap16(buf[0])
+    }
+
+    async fn write32_swapped(&mut self, addr: u32, val: u32) {
+        let cmd = cmd_word(WRITE, INC_ADDR, FUNC_BUS, addr, 4);
+
+        self.status = self.spi.cmd_write(swap16(cmd), &[&[swap16(val)]]).await;
+    }
+}
+
+impl<SPI: SpiBusCyw43> Transport for Spi<SPI> {
+    async fn init(&mut self) -> Result<(), InitError> {
+        let deadline = Instant::now() + BUS_READY_TIMEOUT;
+        while self
+            .read32_swapped(REG_BUS_TEST_RO)
+            .inspect(|v| trace!("{:#x}", v))
+            .await
+            != FEEDBEAD
+        {
+            if Instant::now() > deadline {
+                return Err(InitError::BusNotReady);
+            }
+        }
+
+        self.write32_swapped(REG_BUS_TEST_RW, TEST_PATTERN).await;
+        let val = self.read32_swapped(REG_BUS_TEST_RW).await;
+        trace!("{:#x}", val);
+        if val != TEST_PATTERN {
+            return Err(InitError::BusTest);
+        }
+
+        let val = self.read32_swapped(REG_BUS_CTRL).await;
+        trace!("{:#010b}", (val & 0xff));
+
+        // 32-bit word length, little endian (which is the default endianess).
+        self.write32_swapped(
+            REG_BUS_CTRL,
+            WORD_LENGTH_32 | HIGH_SPEED | INTERRUPT_HIGH | WAKE_UP | STATUS_ENABLE | INTERRUPT_WITH_STATUS,
+        )
+        .await;
+
+        let val = self.readn(FUNC_BUS, REG_BUS_CTRL, 1).await;
+        trace!("{:#b}", val);
+
+        let val = self.readn(FUNC_BUS, REG_BUS_TEST_RO, 4).await;
+        trace!("{:#x}", val);
+        if val != FEEDBEAD {
+            return Err(InitError::BusTest);
+        }
+        let val = self.readn(FUNC_BUS, REG_BUS_TEST_RW, 4).await;
+        trace!("{:#x}", val);
+        if val != TEST_PATTERN {
+            return Err(InitError::BusTest);
+        }
+
+        Ok(())
+    }
+
+    async fn read_reg(&mut self, func: u32, addr: u32, len: u32) -> u32 {
+        self.readn(func, addr, len).await
+    }
+
+    async fn write_reg(&mut self, func: u32, addr: u32, val: u32, len: u32) {
+        self.writen(func, addr, val, len).await
+    }
+
+    async fn bp_read(&mut self, addr: u32, data: &mut [u8]) {
+        // Backplane read buffer has one extra word for the response delay.
+        let mut buf = [0u32; BACKPLANE_MAX_TRANSFER_SIZE / 4 + 1];
+        let len = data.len();
+
+        let cmd = cmd_word(READ, INC_ADDR, FUNC_BACKPLANE, addr, len as u32);
+
+        // round `buf` to word boundary, add one extra word for the response delay
+        self.status = self.spi.cmd_read(cmd, &mut buf[..(len + 3) / 4 + 1]).await;
+
+        // when writing out the data, we skip the response-delay byte
+        data.copy_from_slice(&slice8_mut(&mut buf[1..])[..len]);
+    }
+
+    async fn bp_write(&mut self, addr: u32, data: &[u8]) {
+        let mut buf = [0u32; BACKPLANE_MAX_TRANSFER_SIZE / 4];
+        let len = data.len();
+        slice8_mut(&mut buf)[..len].copy_from_slice(data);
+
+        let cmd = cmd_word(WRITE, INC_ADDR, FUNC_BACKPLANE, addr, len as u32);
+
+        self.status = self.spi.cmd_write(cmd, &[&buf[..(len + 3) / 4]]).await;
+    }
+
+    async fn interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, _chip: &Chip) -> u16 {
+        bus.read16(FUNC_BUS, REG_BUS_INTERRUPT).await
+    }
+
+    async fn clear_interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, _chip: &Chip, irq: u16) {
+        // Interrupt bits are cleared by writing 1.
+        bus.write16(FUNC_BUS, REG_BUS_INTERRUPT, irq).await;
+    }
+
+    async fn enable_interrupts<PWR: OutputPin>(bus: &mut Bus<PWR, Self>, chip: &Chip, irq: u16) {
+        // The Bluetooth core raises F1 interrupts through the SDIOD core, which masks them by default.
+        if irq & IRQ_F1_INTR != 0 {
+            let addr = chip.sdiod_core_base_address + SDIOD_HOST_INT_MASK;
+            bus.bp_write32(addr, SDIOD_I_HMB_FC_CHANGE).await;
+        }
+        bus.write16(FUNC_BUS, REG_BUS_INTERRUPT_ENABLE, irq).await;
+    }
+
+    async fn wlan_ready(&mut self) -> bool {
+        self.readn(FUNC_BUS, REG_BUS_STATUS, 4).await & STATUS_F2_RX_READY != 0
+    }
+
+    async fn wlan_pending(&mut self) -> Option<u32> {
+        // The status word comes with every transaction, no need to ask for it.
+        trace!("check status{}", FormatStatus(self.status));
+
+        if self.status & STATUS_F2_PKT_AVAILABLE != 0 {
+            Some((self.status & STATUS_F2_PKT_LEN_MASK) >> STATUS_F2_PKT_LEN_SHIFT)
+        } else {
+            None
+        }
+    }
+
+    async fn wlan_read(&mut self, buf: &mut [u32], len_in_u8: u32) {
+        let cmd = cmd_word(READ, INC_ADDR, FUNC_WLAN, 0, len_in_u8);
+        let len_in_u32 = (len_in_u8 as usize + 3) / 4;
+
+        self.status = self.spi.cmd_read(cmd, &mut buf[..len_in_u32]).await;
+    }
+
+    async fn wlan_drop(&mut self) {
+        self.writen(
+            FUNC_BACKPLANE,
+            REG_BACKPLANE_FRAME_CONTROL,
+            FRAME_CONTROL_RF_TERM as u32,
+            1,
+        )
+        .await;
+    }
+
+    async fn wlan_write(&mut self, data: &[&[u32]], len: u32) {
+        let cmd = cmd_word(WRITE, INC_ADDR, FUNC_WLAN, 0, len);
+
+        self.status = self.spi.cmd_write(cmd, data).await;
+    }
+
+    async fn wait_for_event(&mut self) {
+        self.spi.wait_for_event().await;
+    }
+}
+
+fn swap16(x: u32) -> u32 {
+    x.rotate_left(16)
+}
+
+fn cmd_word(write: bool, incr: bool, func: u32, addr: u32, len: u32) -> u32 {
+    (write as u32) << 31 | (incr as u32) << 30 | (func & 0b11) << 28 | (addr & 0x1FFFF) << 11 | (len & 0x7FF)
+}
+
+fn slice8_mut(x: &mut [u32]) -> &mut [u8] {
+    let len = x.len() * 4;
+    unsafe { slice::from_raw_parts_mut(x.as_mut_ptr() as _, len) }
+}
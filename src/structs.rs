@@ -33,6 +33,25 @@ pub struct SharedMemData {
 }
 impl_bytes!(SharedMemData);
 
+/// Register dump written by the firmware when it traps.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct TrapInfo {
+    /// Exception type
+    pub trap_type: u32,
+    /// Exception return address
+    pub epc: u32,
+    pub cpsr: u32,
+    pub spsr: u32,
+    /// r0 to r12
+    pub r: [u32; 13],
+    pub sp: u32,
+    pub lr: u32,
+    pub pc: u32,
+}
+impl_bytes!(TrapInfo);
+
 #[derive(Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[repr(C)]
@@ -251,3 +270,53 @@ impl EventMask {
         self.events[evt / 8] &= !(1 << (evt % 8));
     }
 }
+
+/// Packet, byte, multicast and broadcast counts of one traffic direction.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct TrafficStats {
+    pub packets: u32,
+    pub bytes: u32,
+    pub multicast: u32,
+    pub broadcast: u32,
+}
+
+/// Per access category traffic counters kept by the firmware. The arrays are indexed by
+/// [`AccessCategory`](crate::AccessCategory).
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct WmeCounters {
+    pub version: u16,
+    pub length: u16,
+    pub tx: [TrafficStats; 4],
+    pub tx_failed: [TrafficStats; 4],
+    pub rx: [TrafficStats; 4],
+    pub rx_failed: [TrafficStats; 4],
+    pub forward: [TrafficStats; 4],
+    pub tx_expired: [TrafficStats; 4],
+}
+impl_bytes!(WmeCounters);
+
+pub const WME_COUNTERS_VERSION: u16 = 1;
+
+/// Highest transmit power the firmware targets on a channel, per RF core, in quarter dBm.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(C)]
+pub struct TxPowerTargetMax {
+    pub version: u32,
+    pub chanspec: u16,
+    pub txpwr: [i8; 4],
+    pub rf_cores: u8,
+    pub padding: u8,
+}
+impl_bytes!(TxPowerTargetMax);
+
+pub const TXPWR_TARGET_VERSION: u32 = 0;
+/// Length of the firmware's packed `txpwr_target_max`, without the trailing padding of [`TxPowerTargetMax`].
+pub const TXPWR_TARGET_MAX_LEN: usize = 11;
+
+/// Flag of `qtxpower` to ignore the regulatory and board limits. Never set by this driver.
+pub const TXPWR_OVERRIDE: u32 = 1 << 31;
@@ -0,0 +1,23 @@
+/// Highest transmit power limit accepted by [`Control::set_tx_power`](crate::Control::set_tx_power), in dBm.
+pub const MAX_TX_POWER_DBM: u8 = 31;
+
+/// Transmit power, see [`Control::tx_power`](crate::Control::tx_power).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TxPower {
+    /// The limit set with [`Control::set_tx_power`](crate::Control::set_tx_power), in quarter dBm.
+    /// 127 (31.75 dBm) when no limit was set.
+    pub limit_qdbm: u8,
+    /// The power the firmware targets on the current channel, in quarter dBm. This is the limit,
+    /// clamped to the maximum allowed in the current country and to the board limits from the
+    /// NVRAM. `None` if the firmware doesn't report it.
+    pub target_qdbm: Option<i8>,
+}
+
+impl TxPower {
+    /// Whether the country or board maximum is below the limit.
+    pub fn is_clamped(&self) -> bool {
+        self.target_qdbm
+            .map_or(false, |target| (target as i32) < self.limit_qdbm as i32)
+    }
+}
@@ -0,0 +1,18 @@
+/// Find the image CRC in the trailer at the end of a firmware, given its last bytes.
+///
+/// The image is followed by its CRC-32, little endian, then some build information and the version
+/// string. The version string holds the complement of the CRC, e.g. `... CRC: 3317d634 ...`.
+/// Returns the position of the CRC in `tail`, which is where the image ends, and the CRC.
+pub(crate) fn trailer_crc(tail: &[u8]) -> Option<(usize, u32)> {
+    const MARKER: &[u8] = b"-roml/";
+    const CRC_TAG: &[u8] = b" CRC: ";
+
+    let version = tail.windows(MARKER.len()).position(|w| w == MARKER)?;
+    let tag = version + tail[version..].windows(CRC_TAG.len()).position(|w| w == CRC_TAG)?;
+    let hex = tail.get(tag + CRC_TAG.len()..)?.get(..8)?;
+    let hex = core::str::from_utf8(hex).ok()?;
+    let crc = !u32::from_str_radix(hex, 16).ok()?;
+
+    let pos = tail[..version].windows(4).rposition(|w| w == crc.to_le_bytes())?;
+    Some((pos, crc))
+}
@@ -0,0 +1,56 @@
+/// WMM access category. The firmware keeps a TX queue per category, and reports its
+/// [`WmeCounters`](crate::WmeCounters) indexed by `AccessCategory as usize`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AccessCategory {
+    Background = 0,
+    BestEffort = 1,
+    Video = 2,
+    Voice = 3,
+}
+
+impl AccessCategory {
+    /// Number of access categories.
+    pub const COUNT: usize = 4;
+
+    /// Access category of an 802.1D priority, as defined by WMM.
+    pub fn from_priority(priority: u8) -> Self {
+        match priority & 0x07 {
+            1 | 2 => Self::Background,
+            0 | 3 => Self::BestEffort,
+            4 | 5 => Self::Video,
+            _ => Self::Voice,
+        }
+    }
+}
+
+/// Maps an outgoing Ethernet frame to its 802.1D priority (0-7), see
+/// [`Runner::set_tx_classifier`](crate::Runner::set_tx_classifier).
+pub type TxClassifier = fn(&[u8]) -> u8;
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// Default [`TxClassifier`].
+///
+/// Uses the PCP of 802.1Q tagged frames, and the precedence bits (the upper 3 bits of the DSCP)
+/// of IPv4 and IPv6 packets. Everything else is best effort.
+pub fn classify_dscp(frame: &[u8]) -> u8 {
+    match frame.get(12..14).map(|b| u16::from_be_bytes([b[0], b[1]])) {
+        Some(ETHERTYPE_VLAN) => match frame.get(14) {
+            Some(tci) => tci >> 5,
+            None => 0,
+        },
+        Some(ETHERTYPE_IPV4) => match frame.get(15) {
+            Some(tos) => tos >> 5,
+            None => 0,
+        },
+        Some(ETHERTYPE_IPV6) => match frame.get(14) {
+            // The traffic class straddles the first two bytes after the version nibble.
+            Some(b) => (b >> 1) & 0x07,
+            None => 0,
+        },
+        _ => 0,
+    }
+}